@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS contact (
+    id integer PRIMARY KEY,
+    firstname varchar(255),
+    lastname varchar(255) NOT NULL,
+    phone varchar(32),
+    email varchar(255)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE contact DROP COLUMN version;
+ALTER TABLE contact ALTER COLUMN id DROP IDENTITY;
@@ -0,0 +1,3 @@
+-- an INTEGER PRIMARY KEY already aliases the rowid, which SQLite generates,
+-- though it reuses the highest one once deleted until migration 5
+ALTER TABLE contact ADD COLUMN version integer NOT NULL DEFAULT 1;
@@ -0,0 +1,3 @@
+ALTER TABLE contact ALTER COLUMN id ADD GENERATED BY DEFAULT AS IDENTITY;
+SELECT setval(pg_get_serial_sequence('contact', 'id'), COALESCE(max(id), 0) + 1, false) FROM contact;
+ALTER TABLE contact ADD COLUMN version integer NOT NULL DEFAULT 1;
@@ -0,0 +1,3 @@
+DROP INDEX contact_search_trgm_idx;
+DROP INDEX contact_search_idx;
+ALTER TABLE contact DROP COLUMN search;
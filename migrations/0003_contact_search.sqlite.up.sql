@@ -0,0 +1 @@
+-- SQLite has no search column, contacts are matched by the repository
@@ -0,0 +1,8 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm WITH SCHEMA public;
+ALTER TABLE contact ADD COLUMN search tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('simple', COALESCE(firstname, '') || ' ' || lastname), 'A') ||
+    setweight(to_tsvector('simple', translate(COALESCE(email, ''), '@.', '  ')), 'B')
+) STORED;
+CREATE INDEX contact_search_idx ON contact USING gin (search);
+CREATE INDEX contact_search_trgm_idx ON contact
+    USING gin ((COALESCE(firstname, '') || ' ' || lastname || ' ' || COALESCE(email, '')) public.gin_trgm_ops);
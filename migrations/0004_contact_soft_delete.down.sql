@@ -0,0 +1,3 @@
+DELETE FROM contact WHERE deleted_at IS NOT NULL;
+DROP INDEX contact_deleted_at_idx;
+ALTER TABLE contact DROP COLUMN deleted_at;
@@ -0,0 +1,3 @@
+-- milliseconds since the Unix epoch
+ALTER TABLE contact ADD COLUMN deleted_at integer;
+CREATE INDEX contact_deleted_at_idx ON contact (deleted_at) WHERE deleted_at IS NOT NULL;
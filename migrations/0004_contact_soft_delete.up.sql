@@ -0,0 +1,2 @@
+ALTER TABLE contact ADD COLUMN deleted_at timestamptz;
+CREATE INDEX contact_deleted_at_idx ON contact (deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,14 @@
+CREATE TABLE contact_rowid (
+    id integer PRIMARY KEY,
+    firstname varchar(255),
+    lastname varchar(255) NOT NULL,
+    phone varchar(32),
+    email varchar(255),
+    version integer NOT NULL DEFAULT 1,
+    deleted_at integer
+);
+INSERT INTO contact_rowid (id, firstname, lastname, phone, email, version, deleted_at)
+    SELECT id, firstname, lastname, phone, email, version, deleted_at FROM contact;
+DROP TABLE contact;
+ALTER TABLE contact_rowid RENAME TO contact;
+CREATE INDEX contact_deleted_at_idx ON contact (deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,16 @@
+-- without AUTOINCREMENT, SQLite hands out max(rowid) + 1, the id of a purged
+-- contact when it was the highest one; the column cannot be altered in place
+CREATE TABLE contact_autoincrement (
+    id integer PRIMARY KEY AUTOINCREMENT,
+    firstname varchar(255),
+    lastname varchar(255) NOT NULL,
+    phone varchar(32),
+    email varchar(255),
+    version integer NOT NULL DEFAULT 1,
+    deleted_at integer
+);
+INSERT INTO contact_autoincrement (id, firstname, lastname, phone, email, version, deleted_at)
+    SELECT id, firstname, lastname, phone, email, version, deleted_at FROM contact;
+DROP TABLE contact;
+ALTER TABLE contact_autoincrement RENAME TO contact;
+CREATE INDEX contact_deleted_at_idx ON contact (deleted_at) WHERE deleted_at IS NOT NULL;
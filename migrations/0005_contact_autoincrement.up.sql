@@ -0,0 +1,2 @@
+-- identity columns never hand out an id twice, nothing to change
+SELECT 1;
@@ -0,0 +1,253 @@
+//! Bulk upserts of contacts, fed one row at a time and written in batched
+//! transactions, with a report on what happened to every row.
+
+use crate::repository::{Contact, Error, Repository, Upserted};
+use crate::validation::Validate;
+use hyper::body::HttpBody;
+use hyper::Body;
+use serde::Serialize;
+
+/// Rows written per transaction.
+const BATCH_SIZE: usize = 500;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RowStatus {
+    Created,
+    Updated,
+    Failed,
+}
+
+#[derive(Serialize)]
+pub struct RowResult {
+    /// Position of the row in the input, or line of a file, starting at 1.
+    pub row: usize,
+    pub status: RowStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct Report {
+    /// Whether the counts describe what would have been written.
+    pub dry_run: bool,
+    pub created: usize,
+    pub updated: usize,
+    pub failed: usize,
+    pub results: Vec<RowResult>,
+}
+
+impl Report {
+    fn record(&mut self, row: usize, status: RowStatus, id: Option<i32>, error: Option<String>) {
+        match status {
+            RowStatus::Created => self.created += 1,
+            RowStatus::Updated => self.updated += 1,
+            RowStatus::Failed => self.failed += 1,
+        }
+        self.results.push(RowResult { row, status, id, error });
+    }
+}
+
+/// Collects rows, validates them and upserts them a batch at a time. Batches
+/// written before a store failure stay committed.
+pub struct BulkUpsert<'a> {
+    repository: &'a dyn Repository,
+    pending: Vec<(usize, Contact)>,
+    report: Report,
+}
+
+impl<'a> BulkUpsert<'a> {
+    pub fn new(repository: &'a dyn Repository) -> BulkUpsert<'a> {
+        BulkUpsert { repository, pending: Vec::new(), report: Report::default() }
+    }
+
+    /// Reports what would be created or updated without writing anything.
+    pub fn dry_run(repository: &'a dyn Repository) -> BulkUpsert<'a> {
+        BulkUpsert { report: Report { dry_run: true, ..Report::default() }, ..BulkUpsert::new(repository) }
+    }
+
+    /// Adds `row`, or records why it could not be decoded.
+    pub async fn push(&mut self, row: usize, contact: Result<Contact, String>) -> Result<(), Error> {
+        match contact.and_then(|c| c.validate().map(|_| c).map_err(|e| e.to_string())) {
+            Ok(contact) => {
+                self.pending.push((row, contact));
+                if self.pending.len() >= BATCH_SIZE {
+                    self.flush().await?;
+                }
+            }
+            Err(error) => self.report.record(row, RowStatus::Failed, None, Some(error)),
+        }
+        Ok(())
+    }
+
+    /// Writes the remaining rows and returns the report, in row order.
+    pub async fn finish(mut self) -> Result<Report, Error> {
+        self.flush().await?;
+        self.report.results.sort_by_key(|r| r.row);
+        Ok(self.report)
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let (rows, contacts): (Vec<usize>, Vec<Contact>) = self.pending.drain(..).unzip();
+        if self.report.dry_run {
+            for (row, contact) in rows.into_iter().zip(contacts) {
+                // a trashed contact is updated too, `upsert` taking it out of the trash
+                let status = match contact.id {
+                    0 => RowStatus::Created,
+                    id if self.repository.exists(id).await? => RowStatus::Updated,
+                    _ => RowStatus::Created,
+                };
+                self.report.record(row, status, Some(contact.id).filter(|id| *id != 0), None);
+            }
+            return Ok(());
+        }
+        let outcomes = self.repository.upsert(&contacts).await?;
+        for ((row, contact), outcome) in rows.into_iter().zip(&contacts).zip(outcomes) {
+            match outcome {
+                Ok(Upserted::Created(stored)) => self.report.record(row, RowStatus::Created, Some(stored.id), None),
+                Ok(Upserted::Updated(stored)) => self.report.record(row, RowStatus::Updated, Some(stored.id), None),
+                Err(err) => {
+                    let id = Some(contact.id).filter(|id| *id != 0);
+                    self.report.record(row, RowStatus::Failed, id, Some(err.to_string()))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Longest NDJSON line accepted; a longer one fails its row and is skipped.
+pub const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum LineError {
+    /// The line is longer than `MAX_LINE_LENGTH`; reading resumes at the next one.
+    TooLong,
+    Body(hyper::Error),
+}
+
+/// Splits a request body into lines as its chunks arrive, so that an NDJSON
+/// stream is never held in memory as a whole.
+pub struct Lines<'a> {
+    body: &'a mut Body,
+    buf: Vec<u8>,
+    /// Where the next line starts in `buf`.
+    start: usize,
+    /// Bytes of `buf` already known to hold no newline.
+    scanned: usize,
+    /// Whether the bytes up to the next newline belong to a line too long.
+    skipping: bool,
+    done: bool,
+}
+
+impl<'a> Lines<'a> {
+    pub fn new(body: &'a mut Body) -> Lines<'a> {
+        Lines { body, buf: Vec::new(), start: 0, scanned: 0, skipping: false, done: false }
+    }
+
+    /// The next line without its terminator, or `None` at the end of the body.
+    pub async fn next_line(&mut self) -> Option<Result<Vec<u8>, LineError>> {
+        loop {
+            if let Some(end) = self.buf[self.scanned..].iter().position(|b| *b == b'\n').map(|i| self.scanned + i) {
+                let start = std::mem::replace(&mut self.start, end + 1);
+                self.scanned = end + 1;
+                if std::mem::take(&mut self.skipping) {
+                    continue;
+                }
+                if end - start > MAX_LINE_LENGTH {
+                    return Some(Err(LineError::TooLong));
+                }
+                return Some(Ok(self.buf[start..end].to_vec()));
+            }
+            self.scanned = self.buf.len();
+            if self.skipping {
+                self.start = self.buf.len();
+            } else if self.buf.len() - self.start > MAX_LINE_LENGTH {
+                self.start = self.buf.len();
+                self.skipping = true;
+                return Some(Err(LineError::TooLong));
+            }
+            if self.done {
+                let start = std::mem::replace(&mut self.start, self.buf.len());
+                return if start == self.buf.len() { None } else { Some(Ok(self.buf[start..].to_vec())) };
+            }
+            // drop the lines already returned once per chunk, not once per line
+            self.buf.drain(..self.start);
+            self.scanned -= self.start;
+            self.start = 0;
+            match self.body.data().await {
+                Some(Ok(chunk)) => self.buf.extend_from_slice(&chunk),
+                Some(Err(err)) => return Some(Err(LineError::Body(err))),
+                None => self.done = true,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bulk::{BulkUpsert, LineError, Lines, RowStatus, MAX_LINE_LENGTH};
+    use crate::repository::{Contact, InMemoryRepository, Repository};
+    use crate::testing::contact;
+    use hyper::Body;
+
+    #[tokio::test]
+    async fn dry_run_updates_trashed_contacts() {
+        let repository = InMemoryRepository::default();
+        let trashed = repository.save(&contact("doe", "", "")).await.unwrap();
+        repository.delete(trashed.id).await.unwrap();
+
+        let mut bulk = BulkUpsert::dry_run(&repository);
+        bulk.push(1, Ok(trashed.clone())).await.unwrap();
+        bulk.push(2, Ok(Contact { id: trashed.id + 1, ..trashed.clone() })).await.unwrap();
+        let report = bulk.finish().await.unwrap();
+        assert_eq!(report.results[0].status, RowStatus::Updated, "upsert would restore the trashed contact");
+        assert_eq!(report.results[1].status, RowStatus::Created);
+
+        let mut bulk = BulkUpsert::new(&repository);
+        bulk.push(1, Ok(trashed)).await.unwrap();
+        assert_eq!(bulk.finish().await.unwrap().results[0].status, RowStatus::Updated, "dry run should match the real upsert")
+    }
+
+    #[tokio::test]
+    async fn splits_lines_across_chunks() {
+        let chunks: Vec<Result<&str, std::io::Error>> = vec![Ok("{\"a\":1}\n{\"b\""), Ok(":2}\n\n"), Ok("{\"c\":3}")];
+        let mut body = Body::wrap_stream(futures::stream::iter(chunks));
+        let mut lines = Lines::new(&mut body);
+        let mut read = Vec::new();
+        while let Some(line) = lines.next_line().await {
+            read.push(String::from_utf8(line.unwrap()).unwrap());
+        }
+        assert_eq!(read, vec!["{\"a\":1}", "{\"b\":2}", "", "{\"c\":3}"]);
+    }
+
+    #[tokio::test]
+    async fn skips_lines_too_long() {
+        let long = "x".repeat(MAX_LINE_LENGTH / 2);
+        let chunks: Vec<Result<String, std::io::Error>> =
+            vec![Ok("{}\n".to_string()), Ok(long.clone()), Ok(long.clone()), Ok(long), Ok("x\n{\"a\":1}".to_string())];
+        let mut body = Body::wrap_stream(futures::stream::iter(chunks));
+        let mut lines = Lines::new(&mut body);
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), b"{}");
+        assert!(matches!(lines.next_line().await, Some(Err(LineError::TooLong))));
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), b"{\"a\":1}");
+        assert!(lines.next_line().await.is_none())
+    }
+
+    #[tokio::test]
+    async fn rejects_long_lines_read_in_one_chunk() {
+        let chunk = format!("{{}}\n{}\n{{\"a\":1}}\n", "x".repeat(MAX_LINE_LENGTH + 1));
+        let chunks: Vec<Result<String, std::io::Error>> = vec![Ok(chunk)];
+        let mut body = Body::wrap_stream(futures::stream::iter(chunks));
+        let mut lines = Lines::new(&mut body);
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), b"{}");
+        assert!(matches!(lines.next_line().await, Some(Err(LineError::TooLong))), "the newline should not let the line through");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), b"{\"a\":1}");
+        assert!(lines.next_line().await.is_none())
+    }
+}
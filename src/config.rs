@@ -0,0 +1,473 @@
+use crate::logging::{Format, Level};
+use crate::repository::{Backend, PoolConfig};
+use serde::Deserialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+const DEFAULT_BIND: &str = "0.0.0.0:8080";
+const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_READINESS_GRACE_SECS: u64 = 5;
+const DEFAULT_TRASH_RETENTION_DAYS: u64 = 30;
+pub const DEFAULT_MAX_BODY_BYTES: usize = 10 * 1024 * 1024;
+
+/// Validated service configuration.
+///
+/// Settings are layered, each layer overriding the previous one: built-in
+/// defaults, then the TOML file (`--config`, `APP_CONFIG` or `./config.toml`),
+/// then `APP_*` environment variables, then command line flags.
+pub struct Config {
+    pub command: Command,
+    pub bind: SocketAddr,
+    pub drain_timeout: Duration,
+    /// How long readiness probes fail after a shutdown signal before the
+    /// server stops accepting connections.
+    pub readiness_grace: Duration,
+    /// How long deleted contacts stay in the trash before being purged.
+    pub trash_retention: Duration,
+    /// Largest request body read into memory; NDJSON bulk uploads are
+    /// streamed and only limited per line.
+    pub max_body_bytes: usize,
+    pub log_level: Level,
+    pub log_format: Format,
+    pub database_url: String,
+    pub auto_migrate: bool,
+    pub pool: PoolConfig,
+}
+
+/// What the process was asked to do, from the positional arguments.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Command {
+    Serve,
+    MigrateUp,
+    MigrateDown,
+    MigrateStatus,
+}
+
+impl Command {
+    fn parse(words: &[String]) -> Result<Command, ConfigError> {
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        match words.as_slice() {
+            [] | ["serve"] => Ok(Command::Serve),
+            ["migrate", "up"] => Ok(Command::MigrateUp),
+            ["migrate", "down"] => Ok(Command::MigrateDown),
+            ["migrate", "status"] => Ok(Command::MigrateStatus),
+            _ => Err(ConfigError::Invalid(format!(
+                "unknown command '{}' (expected serve or migrate up|down|status)",
+                words.join(" ")
+            ))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "cannot read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "cannot parse {}: {}", path.display(), err),
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct Settings {
+    bind: Option<String>,
+    drain_timeout_secs: Option<u64>,
+    readiness_grace_secs: Option<u64>,
+    trash_retention_days: Option<u64>,
+    max_body_bytes: Option<usize>,
+    log_level: Option<String>,
+    log_format: Option<String>,
+    database: DatabaseSettings,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct DatabaseSettings {
+    url: Option<String>,
+    auto_migrate: Option<bool>,
+    pool_min_size: Option<u32>,
+    pool_max_size: Option<u32>,
+    acquire_timeout_secs: Option<u64>,
+}
+
+impl Settings {
+    /// Values set in `other` win over values set in `self`.
+    fn merge(self, other: Settings) -> Settings {
+        Settings {
+            bind: other.bind.or(self.bind),
+            drain_timeout_secs: other.drain_timeout_secs.or(self.drain_timeout_secs),
+            readiness_grace_secs: other.readiness_grace_secs.or(self.readiness_grace_secs),
+            trash_retention_days: other.trash_retention_days.or(self.trash_retention_days),
+            max_body_bytes: other.max_body_bytes.or(self.max_body_bytes),
+            log_level: other.log_level.or(self.log_level),
+            log_format: other.log_format.or(self.log_format),
+            database: DatabaseSettings {
+                url: other.database.url.or(self.database.url),
+                auto_migrate: other.database.auto_migrate.or(self.database.auto_migrate),
+                pool_min_size: other.database.pool_min_size.or(self.database.pool_min_size),
+                pool_max_size: other.database.pool_max_size.or(self.database.pool_max_size),
+                acquire_timeout_secs: other.database.acquire_timeout_secs.or(self.database.acquire_timeout_secs),
+            },
+        }
+    }
+
+    fn set(&mut self, key: &str, value: String, source: &str) -> Result<(), ConfigError> {
+        match key {
+            "bind" => self.bind = Some(value),
+            "drain-timeout" => self.drain_timeout_secs = Some(parse(source, &value)?),
+            "readiness-grace" => self.readiness_grace_secs = Some(parse(source, &value)?),
+            "trash-retention" => self.trash_retention_days = Some(parse(source, &value)?),
+            "max-body-bytes" => self.max_body_bytes = Some(parse(source, &value)?),
+            "log-level" => self.log_level = Some(value),
+            "log-format" => self.log_format = Some(value),
+            "database-url" => self.database.url = Some(value),
+            "auto-migrate" => {
+                let flag = value.parse().map_err(|_| ConfigError::Invalid(format!("{}: expected true or false, got '{}'", source, value)))?;
+                self.database.auto_migrate = Some(flag)
+            }
+            "pool-min-size" => self.database.pool_min_size = Some(parse(source, &value)?),
+            "pool-max-size" => self.database.pool_max_size = Some(parse(source, &value)?),
+            "acquire-timeout" => self.database.acquire_timeout_secs = Some(parse(source, &value)?),
+            _ => return Err(ConfigError::Invalid(format!("unknown option {}", source))),
+        }
+        Ok(())
+    }
+}
+
+/// Maps each setting to its environment variable, using the flag names as keys.
+const ENV_VARS: &[(&str, &str)] = &[
+    ("bind", "APP_BIND"),
+    ("drain-timeout", "APP_DRAIN_TIMEOUT"),
+    ("readiness-grace", "APP_READINESS_GRACE"),
+    ("trash-retention", "APP_TRASH_RETENTION"),
+    ("max-body-bytes", "APP_MAX_BODY_BYTES"),
+    ("log-level", "APP_LOG_LEVEL"),
+    ("log-format", "APP_LOG_FORMAT"),
+    ("database-url", "APP_DATABASE_URL"),
+    ("auto-migrate", "APP_DATABASE_AUTO_MIGRATE"),
+    ("pool-min-size", "APP_DATABASE_POOL_MIN_SIZE"),
+    ("pool-max-size", "APP_DATABASE_POOL_MAX_SIZE"),
+    ("acquire-timeout", "APP_DATABASE_ACQUIRE_TIMEOUT"),
+];
+
+fn parse<T: FromStr>(source: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::Invalid(format!("{}: invalid number '{}'", source, value)))
+}
+
+impl Config {
+    /// Loads the configuration from the process environment and arguments.
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::from_sources(std::env::args().skip(1), |key| std::env::var(key).ok())
+    }
+
+    pub fn from_sources<A, E>(args: A, env: E) -> Result<Config, ConfigError>
+    where
+        A: IntoIterator<Item = String>,
+        E: Fn(&str) -> Option<String>,
+    {
+        let (config_path, cli, words) = parse_args(args)?;
+        let command = Command::parse(&words)?;
+
+        let file = match config_path.or_else(|| env("APP_CONFIG")) {
+            Some(path) => read_file(Path::new(&path))?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => read_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Settings::default(),
+        };
+
+        let mut from_env = Settings::default();
+        for (key, var) in ENV_VARS {
+            if let Some(value) = env(var) {
+                from_env.set(key, value, var)?;
+            }
+        }
+
+        file.merge(from_env).merge(cli).validate(command)
+    }
+}
+
+/// Splits arguments into the config file path, flag settings and positional words.
+fn parse_args<A: IntoIterator<Item = String>>(args: A) -> Result<(Option<String>, Settings, Vec<String>), ConfigError> {
+    let mut config_path = None;
+    let mut settings = Settings::default();
+    let mut words = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None => {
+                words.push(arg);
+                continue;
+            }
+        };
+        let (key, value) = match flag.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| ConfigError::Invalid(format!("missing value for {}", arg)))?;
+                (flag.to_string(), value)
+            }
+        };
+        if key == "config" {
+            config_path = Some(value);
+        } else {
+            settings.set(&key, value, &format!("--{}", key))?;
+        }
+    }
+    Ok((config_path, settings, words))
+}
+
+fn read_file(path: &Path) -> Result<Settings, ConfigError> {
+    let content = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+    toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+}
+
+impl Settings {
+    fn validate(self, command: Command) -> Result<Config, ConfigError> {
+        let bind_str = self.bind.unwrap_or_else(|| DEFAULT_BIND.to_string());
+        let bind = bind_str
+            .parse()
+            .map_err(|_| ConfigError::Invalid(format!("bind: '{}' is not a valid socket address", bind_str)))?;
+
+        let database_url = self.database.url.filter(|url| !url.trim().is_empty()).ok_or_else(|| {
+            ConfigError::Invalid("database url is required (database.url, APP_DATABASE_URL or --database-url)".to_string())
+        })?;
+        if Backend::from_url(&database_url) == Backend::Postgres {
+            tokio_postgres::Config::from_str(&database_url)
+                .map_err(|e| ConfigError::Invalid(format!("database url: {}", e)))?;
+        }
+
+        let defaults = PoolConfig::default();
+        let pool = PoolConfig {
+            min_size: self.database.pool_min_size.or(defaults.min_size),
+            max_size: self.database.pool_max_size.unwrap_or(defaults.max_size),
+            acquire_timeout: self
+                .database
+                .acquire_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.acquire_timeout),
+            ..defaults
+        };
+        if pool.max_size == 0 {
+            return Err(ConfigError::Invalid("pool max size must be at least 1".to_string()));
+        }
+        if let Some(min_size) = pool.min_size.filter(|min| *min > pool.max_size) {
+            return Err(ConfigError::Invalid(format!(
+                "pool min size ({}) exceeds pool max size ({})",
+                min_size, pool.max_size
+            )));
+        }
+        if pool.acquire_timeout.as_secs() == 0 {
+            return Err(ConfigError::Invalid("acquire timeout must be at least 1 second".to_string()));
+        }
+
+        let drain_timeout = Duration::from_secs(self.drain_timeout_secs.unwrap_or(DEFAULT_DRAIN_TIMEOUT_SECS));
+        let readiness_grace = Duration::from_secs(self.readiness_grace_secs.unwrap_or(DEFAULT_READINESS_GRACE_SECS));
+
+        let trash_retention_days = self.trash_retention_days.unwrap_or(DEFAULT_TRASH_RETENTION_DAYS);
+        if trash_retention_days == 0 {
+            return Err(ConfigError::Invalid("trash retention must be at least 1 day".to_string()));
+        }
+        let trash_retention = trash_retention_days
+            .checked_mul(86_400)
+            .map(Duration::from_secs)
+            .ok_or_else(|| ConfigError::Invalid(format!("trash retention of {} days is too long", trash_retention_days)))?;
+
+        let max_body_bytes = self.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES);
+        if max_body_bytes == 0 {
+            return Err(ConfigError::Invalid("max body bytes must be at least 1".to_string()));
+        }
+
+        let log_level = match self.log_level {
+            Some(level) => level.parse().map_err(|e| ConfigError::Invalid(format!("log level: {}", e)))?,
+            None => Level::Info,
+        };
+        let log_format = match self.log_format {
+            Some(format) => format.parse().map_err(|e| ConfigError::Invalid(format!("log format: {}", e)))?,
+            None => Format::Json,
+        };
+
+        Ok(Config {
+            command,
+            bind,
+            drain_timeout,
+            readiness_grace,
+            trash_retention,
+            max_body_bytes,
+            log_level,
+            log_format,
+            database_url,
+            auto_migrate: self.database.auto_migrate.unwrap_or(false),
+            pool,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Command, Config, ConfigError};
+    use crate::repository::Backend;
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn env(vars: &[(&'static str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<&str, String> = vars.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    fn config_file(content: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn cli_overrides_env_overrides_file() {
+        let file = config_file("bind = \"127.0.0.1:1000\"\n[database]\nurl = \"host=file\"\npool_max_size = 3\n");
+        let path = file.path().to_str().unwrap();
+
+        let config = Config::from_sources(args(&["--config", path]), env(&[])).unwrap();
+        assert_eq!(config.bind.port(), 1000);
+        assert_eq!(config.database_url, "host=file");
+        assert_eq!(config.pool.max_size, 3);
+        assert_eq!(config.drain_timeout.as_secs(), 30);
+        assert_eq!(config.readiness_grace.as_secs(), 5);
+        assert_eq!(config.trash_retention.as_secs(), 30 * 86_400);
+        assert_eq!(config.max_body_bytes, 10 * 1024 * 1024);
+
+        let config = Config::from_sources(
+            args(&["--config", path, "--drain-timeout", "5", "--max-body-bytes", "1024", "--readiness-grace", "0"]),
+            env(&[("APP_BIND", "127.0.0.1:2000"), ("APP_DATABASE_URL", "host=env"), ("APP_TRASH_RETENTION", "7")]),
+        )
+        .unwrap();
+        assert_eq!(config.max_body_bytes, 1024);
+        assert_eq!(config.bind.port(), 2000);
+        assert_eq!(config.database_url, "host=env");
+        assert_eq!(config.drain_timeout.as_secs(), 5);
+        assert_eq!(config.readiness_grace.as_secs(), 0);
+        assert_eq!(config.trash_retention.as_secs(), 7 * 86_400);
+
+        let config = Config::from_sources(
+            args(&["--config", path, "--bind=127.0.0.1:3000", "--database-url", "postgres://cli@localhost/db"]),
+            env(&[("APP_BIND", "127.0.0.1:2000"), ("APP_DATABASE_URL", "host=env")]),
+        )
+        .unwrap();
+        assert_eq!(config.bind.port(), 3000);
+        assert_eq!(config.database_url, "postgres://cli@localhost/db");
+        assert_eq!(config.pool.max_size, 3);
+    }
+
+    /// Arguments reading an empty config file before `extra`, so that the
+    /// checked-in config.toml does not leak into the test.
+    fn without_file<'a>(file: &'a tempfile::NamedTempFile, extra: &[&'a str]) -> Vec<String> {
+        let mut all = vec!["--config", file.path().to_str().unwrap()];
+        all.extend_from_slice(extra);
+        args(&all)
+    }
+
+    #[test]
+    fn commands() {
+        let file = config_file("");
+        let command = |a: &[&str]| Config::from_sources(without_file(&file, a), env(&[("APP_DATABASE_URL", "host=x")])).map(|c| c.command);
+        assert_eq!(command(&[]).unwrap(), Command::Serve);
+        assert_eq!(command(&["serve", "--bind", "127.0.0.1:1"]).unwrap(), Command::Serve);
+        assert_eq!(command(&["migrate", "--log-level", "warn", "up"]).unwrap(), Command::MigrateUp);
+        assert_eq!(command(&["migrate", "down"]).unwrap(), Command::MigrateDown);
+        assert_eq!(command(&["migrate", "status"]).unwrap(), Command::MigrateStatus);
+        assert_eq!(
+            command(&["migrate", "sideways"]).err().unwrap().to_string(),
+            "unknown command 'migrate sideways' (expected serve or migrate up|down|status)"
+        );
+    }
+
+    #[test]
+    fn auto_migrate() {
+        // an empty file, so that the checked-in config.toml does not leak in
+        let file = config_file("");
+        let path = file.path().to_str().unwrap();
+        let config = |e: &[(&'static str, &str)]| Config::from_sources(args(&["--config", path, "--database-url", "host=x"]), env(e));
+        assert!(!config(&[]).unwrap().auto_migrate);
+        assert!(config(&[("APP_DATABASE_AUTO_MIGRATE", "true")]).unwrap().auto_migrate);
+        assert_eq!(
+            config(&[("APP_DATABASE_AUTO_MIGRATE", "yes")]).err().unwrap().to_string(),
+            "APP_DATABASE_AUTO_MIGRATE: expected true or false, got 'yes'"
+        );
+    }
+
+    #[test]
+    fn backend_from_database_url() {
+        let file = config_file("");
+        let config = Config::from_sources(without_file(&file, &["--database-url", "memory://"]), env(&[])).unwrap();
+        assert_eq!(Backend::from_url(&config.database_url), Backend::Memory);
+        let config = Config::from_sources(without_file(&file, &["--database-url", "sqlite://contacts.db"]), env(&[])).unwrap();
+        assert_eq!(Backend::from_url(&config.database_url), Backend::Sqlite);
+        assert!(Config::from_sources(without_file(&file, &["--database-url", "host=x port=nope"]), env(&[])).is_err());
+    }
+
+    #[test]
+    fn config_file_from_env() {
+        let file = config_file("[database]\nurl = \"host=file\"\n");
+        let config = Config::from_sources(args(&[]), env(&[("APP_CONFIG", file.path().to_str().unwrap())])).unwrap();
+        assert_eq!(config.database_url, "host=file");
+    }
+
+    #[test]
+    fn readable_errors() {
+        let file = config_file("");
+        let err = |a: &[&str], e: &[(&'static str, &str)]| Config::from_sources(without_file(&file, a), env(e)).err().unwrap().to_string();
+
+        assert_eq!(err(&["--database-url", "host=x", "--bind", "nope"], &[]), "bind: 'nope' is not a valid socket address");
+        assert_eq!(
+            err(&["--database-url", "host=x"], &[("APP_DATABASE_POOL_MAX_SIZE", "ten")]),
+            "APP_DATABASE_POOL_MAX_SIZE: invalid number 'ten'"
+        );
+        assert_eq!(
+            err(&["--database-url", "host=x", "--pool-min-size", "5", "--pool-max-size", "2"], &[]),
+            "pool min size (5) exceeds pool max size (2)"
+        );
+        assert_eq!(
+            err(&["--database-url", "host=x", "--trash-retention", "0"], &[]),
+            "trash retention must be at least 1 day"
+        );
+        assert_eq!(
+            err(&["--database-url", "host=x", "--trash-retention", "18446744073709551615"], &[]),
+            "trash retention of 18446744073709551615 days is too long"
+        );
+        assert_eq!(err(&["--verbose", "true"], &[]), "unknown option --verbose");
+        assert_eq!(
+            err(&["--database-url", "host=x"], &[("APP_LOG_FORMAT", "xml")]),
+            "log format: unknown log format 'xml' (expected json or logfmt)"
+        );
+        assert_eq!(err(&["--bind"], &[]), "missing value for --bind");
+        assert!(err(&["--database-url", "postgres://[bad"], &[]).starts_with("database url:"));
+        assert!(err(&["--database-url", ""], &[]).starts_with("database url is required"));
+    }
+
+    #[test]
+    fn unknown_file_keys_are_rejected() {
+        let file = config_file("[database]\nurl = \"host=file\"\npassword = \"x\"\n");
+        let err = Config::from_sources(args(&["--config", file.path().to_str().unwrap()]), env(&[])).err().unwrap();
+        assert!(matches!(err, ConfigError::Parse(_, _)), "{}", err)
+    }
+}
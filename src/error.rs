@@ -0,0 +1,157 @@
+use crate::logging::{log, Level};
+use crate::repository::Error as RepositoryError;
+use crate::validation::ValidationErrors;
+use crate::router::IntoResponse;
+use crate::Response;
+use hyper::{header, StatusCode};
+use serde_json::json;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    NotFound,
+    NotAcceptable,
+    /// The request body is larger than the limit, in bytes.
+    PayloadTooLarge(usize),
+    Validation(ValidationErrors),
+    Repository(RepositoryError),
+    Internal(String),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(detail) => write!(f, "bad request: {}", detail),
+            ApiError::NotFound => write!(f, "not found"),
+            ApiError::NotAcceptable => write!(f, "not acceptable"),
+            ApiError::PayloadTooLarge(limit) => write!(f, "request body larger than {} bytes", limit),
+            ApiError::Validation(errors) => write!(f, "{}", errors),
+            ApiError::Repository(err) => write!(f, "{}", err),
+            ApiError::Internal(err) => write!(f, "internal error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<RepositoryError> for ApiError {
+    fn from(err: RepositoryError) -> ApiError {
+        ApiError::Repository(err)
+    }
+}
+
+impl From<ValidationErrors> for ApiError {
+    fn from(errors: ValidationErrors) -> ApiError {
+        ApiError::Validation(errors)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            ApiError::BadRequest(detail) => problem(StatusCode::BAD_REQUEST, &detail),
+            ApiError::NotFound => problem(StatusCode::NOT_FOUND, "resource not found"),
+            ApiError::NotAcceptable => {
+                problem(StatusCode::NOT_ACCEPTABLE, "acceptable types are application/json, text/csv and application/msgpack")
+            }
+            ApiError::PayloadTooLarge(limit) => {
+                problem(StatusCode::PAYLOAD_TOO_LARGE, &format!("the request body is larger than {} bytes", limit))
+            }
+            ApiError::Validation(errors) => {
+                let body = json!({
+                    "type": "about:blank",
+                    "title": "Unprocessable Entity",
+                    "status": StatusCode::UNPROCESSABLE_ENTITY.as_u16(),
+                    "detail": errors.to_string(),
+                    "errors": errors.errors,
+                });
+                problem_response(StatusCode::UNPROCESSABLE_ENTITY, body)
+            }
+            ApiError::Repository(err) => err.into_response(),
+            ApiError::Internal(err) => {
+                log!(Level::Error, "internal error", error = err);
+                problem(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+            }
+        }
+    }
+}
+
+impl IntoResponse for RepositoryError {
+    fn into_response(self) -> Response {
+        match self {
+            RepositoryError::NotFound => problem(StatusCode::NOT_FOUND, "resource not found"),
+            RepositoryError::VersionMismatch => {
+                problem(StatusCode::PRECONDITION_FAILED, "the contact has changed since it was read, fetch it again before updating")
+            }
+            RepositoryError::Unavailable => problem(StatusCode::SERVICE_UNAVAILABLE, "database unavailable"),
+            RepositoryError::Sqlite(err) => {
+                log!(Level::Error, "database error", error = err.to_string());
+                problem(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+            }
+            RepositoryError::Db(err) => {
+                log!(Level::Error, "database error", error = err.to_string());
+                problem(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+            }
+            RepositoryError::Intern(err) => {
+                log!(Level::Error, "internal error", error = err);
+                problem(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+            }
+        }
+    }
+}
+
+/// Builds an `application/problem+json` response as described in RFC 7807.
+pub fn problem(status: StatusCode, detail: &str) -> Response {
+    let body = json!({
+        "type": "about:blank",
+        "title": status.canonical_reason().unwrap_or(""),
+        "status": status.as_u16(),
+        "detail": detail,
+    });
+    problem_response(status, body)
+}
+
+fn problem_response(status: StatusCode, body: serde_json::Value) -> Response {
+    hyper::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/problem+json")
+        .body(body.to_string().into())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ApiError;
+    use crate::repository::Error as RepositoryError;
+    use crate::router::IntoResponse;
+    use crate::validation::Validator;
+    use hyper::{body::to_bytes, header, StatusCode};
+
+    #[tokio::test]
+    async fn bad_request_is_400_with_problem_body() {
+        let resp = ApiError::BadRequest("invalid id 'foo'".to_string()).into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/problem+json");
+        let body: serde_json::Value = serde_json::from_slice(&to_bytes(resp.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["status"], 400);
+        assert_eq!(body["detail"], "invalid id 'foo'");
+    }
+
+    #[tokio::test]
+    async fn validation_is_422_listing_fields() {
+        let errors = Validator::new().required("lastname", "").email("email", "nope").finish().unwrap_err();
+        let resp = ApiError::from(errors).into_response();
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(&to_bytes(resp.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["errors"][0]["field"], "lastname");
+        assert_eq!(body["errors"][1]["field"], "email");
+    }
+
+    #[test]
+    fn repository_errors_map_to_status() {
+        assert_eq!(ApiError::from(RepositoryError::NotFound).into_response().status(), StatusCode::NOT_FOUND);
+        assert_eq!(RepositoryError::VersionMismatch.into_response().status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(RepositoryError::Intern("boom".to_string()).into_response().status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,236 @@
+//! Typed handler arguments, built from the request `Context` before the
+//! handler runs. A handler taking extractors, such as
+//! `async fn get(Path(id): Path<i32>, State(state): State<Arc<AppState>>)`,
+//! can be registered like one taking the whole `Context`.
+
+use crate::error::ApiError;
+use crate::router::IntoResponse;
+use crate::validation::Validate;
+use crate::{AppState, Context, Response};
+use async_trait::async_trait;
+use hyper::header;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// A value extracted from the request. Failures are answered with the
+/// returned error, a 400 for malformed input, without calling the handler.
+#[async_trait]
+pub trait FromContext: Sized {
+    async fn from_context(ctx: &mut Context) -> Result<Self, ApiError>;
+
+    /// Like `from_context`, but `None` when the request lacks the value
+    /// altogether, for `Option<Self>`. An invalid value is still an error.
+    async fn from_context_optional(ctx: &mut Context) -> Result<Option<Self>, ApiError> {
+        Self::from_context(ctx).await.map(Some)
+    }
+}
+
+/// The single parameter of the matched route, such as `:id` in
+/// `/contacts/:id`.
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<T: FromStr> FromContext for Path<T> {
+    async fn from_context(ctx: &mut Context) -> Result<Self, ApiError> {
+        let mut params = ctx.params.iter();
+        let (name, value) = match (params.next(), params.next()) {
+            (Some(param), None) => param,
+            // a misregistered handler, not a bad request
+            _ => return Err(ApiError::Internal(format!("Path needs a route with one parameter, not {}", ctx.params.iter().count()))),
+        };
+        value.parse().map(Path).map_err(|_| ApiError::BadRequest(format!("invalid {} '{}'", name, value)))
+    }
+}
+
+/// The query string, deserialized into `T`.
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned> FromContext for Query<T> {
+    async fn from_context(ctx: &mut Context) -> Result<Self, ApiError> {
+        ctx.query().map(Query).map_err(|e| ApiError::BadRequest(e.to_string()))
+    }
+
+    async fn from_context_optional(ctx: &mut Context) -> Result<Option<Self>, ApiError> {
+        match ctx.req.uri().query() {
+            None | Some("") => Ok(None),
+            Some(_) => Self::from_context(ctx).await.map(Some),
+        }
+    }
+}
+
+/// The JSON request body, deserialized into `T`. As a response, `T` serialized
+/// as `application/json`.
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned> FromContext for Json<T> {
+    async fn from_context(ctx: &mut Context) -> Result<Self, ApiError> {
+        ctx.body_json().await.map(Json)
+    }
+}
+
+impl<T: Serialize + Send> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        match serde_json::to_vec(&self.0) {
+            Ok(body) => hyper::Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body.into())
+                .unwrap(),
+            Err(err) => ApiError::Internal(err.to_string()).into_response(),
+        }
+    }
+}
+
+/// The JSON request body, deserialized then validated, failing with 422 when
+/// the value breaks its validation rules.
+pub struct Valid<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned + Validate + Send> FromContext for Valid<T> {
+    async fn from_context(ctx: &mut Context) -> Result<Self, ApiError> {
+        ctx.valid_json().await.map(Valid)
+    }
+}
+
+/// A header that can be extracted with `Header<T>`.
+pub trait NamedHeader: Sized {
+    const NAME: &'static str;
+
+    fn parse(value: &str) -> Option<Self>;
+}
+
+/// A request header, decoded by `T`. Use `Option<Header<T>>` when the header is optional.
+pub struct Header<T>(pub T);
+
+#[async_trait]
+impl<T: NamedHeader> FromContext for Header<T> {
+    async fn from_context_optional(ctx: &mut Context) -> Result<Option<Self>, ApiError> {
+        if ctx.req.headers().contains_key(T::NAME) {
+            Self::from_context(ctx).await.map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn from_context(ctx: &mut Context) -> Result<Self, ApiError> {
+        let value = ctx
+            .req
+            .headers()
+            .get(T::NAME)
+            .ok_or_else(|| ApiError::BadRequest(format!("missing header {}", T::NAME)))?;
+        value
+            .to_str()
+            .ok()
+            .and_then(T::parse)
+            .map(Header)
+            .ok_or_else(|| ApiError::BadRequest(format!("invalid header {}", T::NAME)))
+    }
+}
+
+/// Shared application state.
+pub struct State<T>(pub T);
+
+#[async_trait]
+impl FromContext for State<Arc<AppState>> {
+    async fn from_context(ctx: &mut Context) -> Result<Self, ApiError> {
+        Ok(State(ctx.state.clone()))
+    }
+}
+
+#[async_trait]
+impl<T: FromContext + Send> FromContext for Option<T> {
+    async fn from_context(ctx: &mut Context) -> Result<Self, ApiError> {
+        T::from_context_optional(ctx).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::extract::{Header, Json, NamedHeader, Path, Query};
+    use crate::router::Router;
+    use crate::testing::TestApp;
+    use hyper::{Body, Method, Request, StatusCode};
+    use serde::Deserialize;
+
+    struct Tenant(String);
+
+    impl NamedHeader for Tenant {
+        const NAME: &'static str = "x-tenant";
+
+        fn parse(value: &str) -> Option<Self> {
+            Some(Tenant(value.to_string())).filter(|t| !t.0.is_empty())
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct Page {
+        size: u32,
+    }
+
+    #[derive(Deserialize)]
+    struct Name {
+        name: String,
+    }
+
+    async fn describe(Path(id): Path<u8>, Query(page): Query<Page>, tenant: Option<Header<Tenant>>, Json(body): Json<Name>) -> String {
+        let tenant = tenant.map(|Header(Tenant(t))| t).unwrap_or_default();
+        format!("{} {} {} {}", id, page.size, tenant, body.name)
+    }
+
+    async fn ambiguous(Path(id): Path<u8>) -> String {
+        id.to_string()
+    }
+
+    fn app() -> TestApp {
+        let mut router = Router::new();
+        router.post("/things/:id", describe);
+        router.get("/owners/:owner/tools/:id", ambiguous);
+        TestApp::with_router(router)
+    }
+
+    #[tokio::test]
+    async fn extracts_typed_arguments() {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/things/7?size=20")
+            .header("x-tenant", "acme")
+            .body(Body::from(r#"{"name":"bolt"}"#))
+            .unwrap();
+        let resp = app().request(req).await;
+        assert_eq!(resp.status, StatusCode::OK);
+        assert_eq!(resp.text(), "7 20 acme bolt");
+    }
+
+    #[tokio::test]
+    async fn extraction_failure_is_bad_request() {
+        let app = app();
+        let resp = app.send(Method::POST, "/things/300?size=1", Some(r#"{"name":"bolt"}"#)).await;
+        assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+        assert_eq!(resp.json()["detail"], "invalid id '300'");
+        assert_eq!(app.send(Method::POST, "/things/1", Some(r#"{"name":"bolt"}"#)).await.status, StatusCode::BAD_REQUEST);
+        assert_eq!(app.send(Method::POST, "/things/1?size=1", Some("{")).await.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn optional_extractors_reject_invalid_values() {
+        let app = app();
+        let resp = app.send(Method::POST, "/things/7?size=1", Some(r#"{"name":"bolt"}"#)).await;
+        assert_eq!(resp.text(), "7 1  bolt", "a missing header should be None");
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/things/7?size=1")
+            .header("x-tenant", "")
+            .body(Body::from(r#"{"name":"bolt"}"#))
+            .unwrap();
+        assert_eq!(app.request(req).await.status, StatusCode::BAD_REQUEST, "an invalid header should not be None");
+    }
+
+    #[tokio::test]
+    async fn path_needs_a_single_parameter() {
+        let app = app();
+        assert_eq!(app.get("/owners/zed/tools/3").await.status, StatusCode::INTERNAL_SERVER_ERROR, "Path should not pick one of several parameters");
+    }
+}
@@ -1,13 +1,520 @@
-use crate::{Context};
+use crate::{AppState, Context, Response};
 use serde_json::json;
-use crate::repository::Repository;
+use hyper::{header, Body, StatusCode};
+use serde::Deserialize;
+use std::sync::Arc;
+use crate::bulk::{BulkUpsert, LineError, Lines, Report, MAX_LINE_LENGTH};
+use crate::error::ApiError;
+use crate::extract::{FromContext, Header, Json, NamedHeader, Path, Query, State, Valid};
+use crate::interchange::{FileFormat, Mapping, CSV_COLUMNS};
+use crate::logging::{log, Level};
+use crate::negotiate::Format;
+use crate::query::{ContactQuery, TrashQuery, MAX_LIMIT};
+use crate::router::IntoResponse;
+use crate::repository::{Contact, ContactPatch};
+use crate::search::{self, SearchQuery, SearchResult};
 
 
-pub async fn get_contact(ctx: Context) -> String {
-    let id = match ctx.params.find("id") {
-        Some(v) => v,
-        None => "empty",
-    }.parse().unwrap();
-    let contact = ctx.state.repository.get(id).await.unwrap();
-    json!(&contact).to_string()
+pub async fn get_contact(State(state): State<Arc<AppState>>, Path(id): Path<i32>, format: Format) -> Result<Response, ApiError> {
+    let contact = state.repository.get(id).await?;
+    Ok(with_etag(format.render(StatusCode::OK, &contact)?, &contact))
+}
+
+pub async fn list_contacts(mut ctx: Context) -> Result<Response, ApiError> {
+    let format = Format::from_context(&mut ctx).await?;
+    let query: ContactQuery = ctx.query().map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let page = ctx.state.repository.list(&query).await?;
+    let next = page.next_cursor.map(|cursor| next_link(&ctx, cursor));
+    let body = json!({
+        "items": page.items,
+        "total": page.total,
+        "next_cursor": page.next_cursor,
+        "links": { "next": next },
+    });
+    format.render_list(StatusCode::OK, &body, &CSV_COLUMNS, &page.items)
+}
+
+/// Ranks the contacts matching `q` and highlights what matched.
+pub async fn search_contacts(State(state): State<Arc<AppState>>, Query(query): Query<SearchQuery>) -> Result<Json<serde_json::Value>, ApiError> {
+    if query.q.trim().is_empty() {
+        return Err(ApiError::BadRequest("q must not be empty".to_string()));
+    }
+    let terms = search::terms(&query.q);
+    let hits = state.repository.search(&query.q, query.limit()).await?;
+    let items: Vec<SearchResult> = hits.into_iter().map(|hit| SearchResult::new(hit, &terms)).collect();
+    Ok(Json(json!({ "items": items })))
+}
+
+pub async fn create_contact(State(state): State<Arc<AppState>>, Valid(contact): Valid<Contact>) -> Result<Response, ApiError> {
+    let contact = state.repository.save(&contact).await?;
+    let mut resp = with_etag(Json(&contact).into_response(), &contact);
+    *resp.status_mut() = StatusCode::CREATED;
+    resp.headers_mut().insert(header::LOCATION, format!("/contacts/{}", contact.id).parse().unwrap());
+    Ok(resp)
+}
+
+pub async fn update_contact(State(state): State<Arc<AppState>>, Path(id): Path<i32>, if_match: Option<Header<IfMatch>>, Valid(mut contact): Valid<Contact>) -> Result<Response, ApiError> {
+    contact.id = id;
+    let contact = state.repository.update(&contact, expected_version(if_match)).await?;
+    Ok(with_etag(Json(&contact).into_response(), &contact))
+}
+
+pub async fn patch_contact(State(state): State<Arc<AppState>>, Path(id): Path<i32>, if_match: Option<Header<IfMatch>>, Valid(patch): Valid<ContactPatch>) -> Result<Response, ApiError> {
+    let mut contact = state.repository.get(id).await?;
+    // without If-Match, still refuse to overwrite a change made since the read
+    let expected = expected_version(if_match).unwrap_or(contact.version);
+    contact.apply(patch);
+    let contact = state.repository.update(&contact, Some(expected)).await?;
+    Ok(with_etag(Json(&contact).into_response(), &contact))
+}
+
+pub async fn delete_contact(State(state): State<Arc<AppState>>, Path(id): Path<i32>) -> Result<Response, ApiError> {
+    match state.repository.delete(id).await? {
+        0 => Err(ApiError::NotFound),
+        _ => Ok(status_response(StatusCode::NO_CONTENT)),
+    }
+}
+
+/// Lists the deleted contacts that have not been purged yet.
+pub async fn list_trash(ctx: Context) -> Result<Json<serde_json::Value>, ApiError> {
+    let query: TrashQuery = ctx.query().map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let page = ctx.state.repository.trash(query.limit(), query.after).await?;
+    let next = page.next_cursor.map(|cursor| next_link(&ctx, cursor));
+    Ok(Json(json!({
+        "items": page.items,
+        "total": page.total,
+        "next_cursor": page.next_cursor,
+        "links": { "next": next },
+    })))
+}
+
+pub async fn restore_contact(State(state): State<Arc<AppState>>, Path(id): Path<i32>) -> Result<Response, ApiError> {
+    let contact = state.repository.restore(id).await?;
+    Ok(with_etag(Json(&contact).into_response(), &contact))
+}
+
+/// Upserts the contacts of a JSON array, or of an NDJSON stream read as it
+/// arrives, and reports on each row instead of failing on the first bad one.
+pub async fn bulk_contacts(mut ctx: Context) -> Result<Json<Report>, ApiError> {
+    let state = ctx.state.clone();
+    let mut bulk = BulkUpsert::new(&*state.repository);
+    if is_ndjson(&ctx) {
+        let mut lines = Lines::new(ctx.req.body_mut());
+        let mut row = 0;
+        while let Some(line) = lines.next_line().await {
+            row += 1;
+            let line = match line {
+                Ok(line) => line,
+                Err(LineError::TooLong) => {
+                    bulk.push(row, Err(format!("line longer than {} bytes", MAX_LINE_LENGTH))).await?;
+                    continue;
+                }
+                Err(LineError::Body(e)) => return Err(ApiError::BadRequest(e.to_string())),
+            };
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            bulk.push(row, serde_json::from_slice(&line).map_err(|e| e.to_string())).await?;
+        }
+    } else {
+        let rows: Vec<serde_json::Value> = ctx.body_json().await?;
+        for (i, value) in rows.into_iter().enumerate() {
+            bulk.push(i + 1, serde_json::from_value(value).map_err(|e| e.to_string())).await?;
+        }
+    }
+    Ok(Json(bulk.finish().await?))
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    #[serde(default)]
+    format: FileFormat,
+}
+
+/// Streams every contact as CSV or vCard, reading a page at a time so that
+/// the whole address book is never held in memory.
+pub async fn export_contacts(State(state): State<Arc<AppState>>, Query(params): Query<ExportParams>) -> Response {
+    let format = params.format;
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut query = ContactQuery { limit: Some(MAX_LIMIT), ..ContactQuery::default() };
+        let mut first = true;
+        loop {
+            let page = match state.repository.list(&query).await {
+                Ok(page) => page,
+                Err(err) => {
+                    // the status is already sent, cut the body short so the client sees a failure
+                    log!(Level::Error, "export failed", error = err.to_string());
+                    sender.abort();
+                    return;
+                }
+            };
+            if sender.send_data(format.encode(&page.items, first).into()).await.is_err() {
+                return;
+            }
+            first = false;
+            match page.next_cursor {
+                Some(cursor) => query.after = Some(cursor),
+                None => return,
+            }
+        }
+    });
+    hyper::Response::builder()
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"contacts.{}\"", format.extension()))
+        .body(body)
+        .unwrap()
+}
+
+#[derive(Deserialize)]
+pub struct ImportParams {
+    /// Defaults to the format of the `Content-Type`.
+    format: Option<FileFormat>,
+    #[serde(default)]
+    dry_run: bool,
+    /// Extra CSV column mapping, such as `Given Name:firstname,Surname:lastname`.
+    #[serde(default)]
+    mapping: String,
+}
+
+/// Imports a CSV or vCard file, reporting on each line, or only checking it
+/// with `dry_run=true`.
+pub async fn import_contacts(mut ctx: Context) -> Result<Json<Report>, ApiError> {
+    let params: ImportParams = ctx.query().map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let content_type = ctx.req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let format = params
+        .format
+        .or_else(|| FileFormat::from_content_type(content_type))
+        .ok_or_else(|| ApiError::BadRequest("unknown file format, send text/csv or text/vcard, or set format".to_string()))?;
+    let mapping = Mapping::parse(&params.mapping).map_err(ApiError::BadRequest)?;
+    let body = ctx.body_bytes().await?;
+    let rows = format.decode(body, &mapping).map_err(ApiError::BadRequest)?;
+
+    let state = ctx.state.clone();
+    let mut bulk = if params.dry_run { BulkUpsert::dry_run(&*state.repository) } else { BulkUpsert::new(&*state.repository) };
+    for (line, contact) in rows {
+        bulk.push(line, contact).await?;
+    }
+    Ok(Json(bulk.finish().await?))
+}
+
+fn is_ndjson(ctx: &Context) -> bool {
+    let content_type = ctx.req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    media_type.eq_ignore_ascii_case("application/x-ndjson") || media_type.eq_ignore_ascii_case("application/ndjson")
+}
+
+/// Rebuilds the current URL with `after` pointing at the cursor, keeping every
+/// other query parameter except `offset`, which does not combine with cursors.
+fn next_link(ctx: &Context, cursor: i32) -> String {
+    let mut pairs: Vec<(String, String)> = serde_urlencoded::from_str(ctx.req.uri().query().unwrap_or("")).unwrap_or_default();
+    pairs.retain(|(key, _)| key != "after" && key != "offset");
+    pairs.push(("after".to_string(), cursor.to_string()));
+    format!("{}?{}", ctx.req.uri().path(), serde_urlencoded::to_string(pairs).unwrap_or_default())
+}
+
+/// The version a client expects to replace, from `If-Match: "<version>"`,
+/// `None` for `*`. Without the header, the update is unconditional.
+pub struct IfMatch(pub Option<i32>);
+
+impl NamedHeader for IfMatch {
+    const NAME: &'static str = "If-Match";
+
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "*" => Some(IfMatch(None)),
+            value => value.strip_prefix('"')?.strip_suffix('"')?.parse().ok().map(|version| IfMatch(Some(version))),
+        }
+    }
+}
+
+fn expected_version(if_match: Option<Header<IfMatch>>) -> Option<i32> {
+    if_match.and_then(|Header(IfMatch(version))| version)
+}
+
+fn with_etag(mut resp: Response, contact: &Contact) -> Response {
+    resp.headers_mut().insert(header::ETAG, format!("\"{}\"", contact.version).parse().unwrap());
+    resp
+}
+
+fn status_response(status: StatusCode) -> Response {
+    hyper::Response::builder()
+        .status(status)
+        .body(hyper::Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::list_contacts;
+    use crate::repository::{Contact, InMemoryRepository};
+    use crate::router::IntoResponse;
+    use crate::testing::{contact, json_body, TestApp};
+    use crate::{AppState, Context};
+    use hyper::{Body, Method, Request, StatusCode};
+    use route_recognizer::Params;
+    use std::sync::Arc;
+
+    fn state() -> Arc<AppState> {
+        Arc::new(AppState::new(Arc::new(InMemoryRepository::default())))
+    }
+
+    fn context(state: &Arc<AppState>, method: &str, uri: &str, body: &str) -> Context {
+        let req = Request::builder().method(method).uri(uri).body(Body::from(body.to_string())).unwrap();
+        Context::new(state.clone(), req, Params::new())
+    }
+
+    #[tokio::test]
+    async fn create_get_patch_delete() {
+        let app = TestApp::new();
+        let contact = r#"{"firstname":"Ada","lastname":"Lovelace","phone":"","email":"ada@example.com"}"#;
+        let resp = app.send(Method::POST, "/contacts", Some(contact)).await;
+        assert_eq!(resp.status, StatusCode::CREATED);
+        assert_eq!(resp.header("location"), Some("/contacts/1"));
+
+        let resp = app.send(Method::POST, "/contacts", Some(contact)).await;
+        assert_eq!(resp.header("location"), Some("/contacts/2"), "ids should be generated");
+
+        let resp = app.send(Method::PATCH, "/contacts/1", Some(r#"{"firstname":"Augusta"}"#)).await;
+        assert_eq!(resp.status, StatusCode::OK);
+        assert_eq!(app.get("/contacts/1").await.json()["firstname"], "Augusta");
+
+        let resp = app.send(Method::DELETE, "/contacts/1", None).await;
+        assert_eq!(resp.status, StatusCode::NO_CONTENT);
+        assert_eq!(app.get("/contacts/1").await.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn list_links_next_page() {
+        let state = state();
+        let contact = contact("doe", "", "");
+        for _ in 1..=3 {
+            state.repository.save(&contact).await.unwrap();
+        }
+        let resp = list_contacts(context(&state, "GET", "/contacts?limit=2&offset=0", "")).await.into_response();
+        let body = json_body(resp).await;
+        assert_eq!(body["total"], 3);
+        assert_eq!(body["items"].as_array().unwrap().len(), 2);
+        assert_eq!(body["links"]["next"], "/contacts?limit=2&after=2");
+    }
+
+    #[tokio::test]
+    async fn if_match_rejects_stale_versions() {
+        let app = TestApp::new();
+        let contact = r#"{"firstname":"Ada","lastname":"Lovelace","phone":"","email":""}"#;
+        let resp = app.send(Method::POST, "/contacts", Some(contact)).await;
+        assert_eq!(resp.header("etag"), Some("\"1\""));
+        assert_eq!(resp.json()["id"], 1);
+        assert_eq!(app.get("/contacts/1").await.header("etag"), Some("\"1\""));
+
+        let update = |version: &str, body: &'static str| {
+            let req = Request::patch("/contacts/1").header("if-match", version).body(Body::from(body)).unwrap();
+            app.request(req)
+        };
+        let resp = update("\"1\"", r#"{"firstname":"Augusta"}"#).await;
+        assert_eq!(resp.status, StatusCode::OK);
+        assert_eq!(resp.header("etag"), Some("\"2\""));
+        assert_eq!(resp.json()["version"], 2);
+
+        let resp = update("\"1\"", r#"{"firstname":"Ada"}"#).await;
+        assert_eq!(resp.status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(app.get("/contacts/1").await.json()["firstname"], "Augusta");
+        assert_eq!(update("2", "{}").await.status, StatusCode::BAD_REQUEST);
+
+        let req = Request::put("/contacts/1").header("if-match", "\"1\"").body(Body::from(contact)).unwrap();
+        assert_eq!(app.request(req).await.status, StatusCode::PRECONDITION_FAILED);
+        let req = Request::put("/contacts/1").header("if-match", "*").body(Body::from(contact)).unwrap();
+        assert_eq!(app.request(req).await.header("etag"), Some("\"3\""));
+    }
+
+    #[tokio::test]
+    async fn bulk_upserts_json_array() {
+        let app = TestApp::new();
+        let rows = r#"[
+            {"firstname":"Ada","lastname":"Lovelace","phone":"","email":""},
+            {"id":10,"firstname":"Alan","lastname":"Turing","phone":"","email":""},
+            {"firstname":"","lastname":"","phone":"","email":""},
+            {"lastname":42}
+        ]"#;
+        let report = app.send(Method::POST, "/contacts/bulk", Some(rows)).await.json();
+        assert_eq!((report["created"].as_u64(), report["updated"].as_u64(), report["failed"].as_u64()), (Some(2), Some(0), Some(2)));
+        assert_eq!(report["results"][1]["id"], 10);
+        assert_eq!(report["results"][2]["status"], "failed");
+        assert_eq!(report["results"][3]["row"], 4);
+
+        let rows = r#"[{"id":10,"firstname":"Alan","lastname":"Kay","phone":"","email":""}]"#;
+        let report = app.send(Method::POST, "/contacts/bulk", Some(rows)).await.json();
+        assert_eq!(report["results"][0]["status"], "updated");
+        let resp = app.get("/contacts/10").await;
+        assert_eq!((resp.json()["lastname"].as_str(), resp.json()["version"].as_i64()), (Some("Kay"), Some(2)));
+        let contact = r#"{"firstname":"","lastname":"Hopper","phone":"","email":""}"#;
+        assert_eq!(app.send(Method::POST, "/contacts", Some(contact)).await.json()["id"], 11, "generated ids should skip upserted ones");
+
+        assert_eq!(app.send(Method::POST, "/contacts/bulk", Some("{}")).await.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn bulk_upserts_ndjson_stream() {
+        let body = "{\"firstname\":\"Ada\",\"lastname\":\"Lovelace\",\"phone\":\"\",\"email\":\"\"}\n\n{\n{\"firstname\":\"\",\"lastname\":\"Hopper\",\"phone\":\"\",\"email\":\"\"}";
+        let req = Request::post("/contacts/bulk").header("content-type", "application/x-ndjson").body(Body::from(body)).unwrap();
+        let report = TestApp::new().request(req).await.json();
+        assert_eq!((report["created"].as_u64(), report["failed"].as_u64()), (Some(2), Some(1)));
+        let rows: Vec<u64> = report["results"].as_array().unwrap().iter().map(|r| r["row"].as_u64().unwrap()).collect();
+        assert_eq!(rows, vec![1, 3, 4], "blank lines should be skipped but counted");
+    }
+
+    #[tokio::test]
+    async fn export_streams_every_contact() {
+        let app = TestApp::new();
+        let contacts: Vec<Contact> = (0..150)
+            .map(|i| contact(&format!("doe{}", i), "", ""))
+            .collect();
+        app.state.repository.upsert(&contacts).await.unwrap();
+
+        let resp = app.get("/contacts/export").await;
+        assert_eq!(resp.header("content-type"), Some("text/csv; charset=utf-8"));
+        assert_eq!(resp.header("content-disposition"), Some("attachment; filename=\"contacts.csv\""));
+        let lines: Vec<&str> = resp.text().lines().collect();
+        assert_eq!(lines.len(), 151, "every page should be exported after a single header");
+        assert_eq!(lines[150], "150,,doe149,,,1");
+
+        let resp = app.get("/contacts/export?format=vcf").await;
+        assert_eq!(resp.text().matches("BEGIN:VCARD").count(), 150);
+        assert_eq!(app.get("/contacts/export?format=xml").await.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn rejects_bodies_over_the_limit() {
+        let app = TestApp::with_state(AppState { max_body_bytes: 80, ..AppState::new(Arc::new(InMemoryRepository::default())) });
+        let contact = r#"{"firstname":"Ada","lastname":"Lovelace","phone":"","email":"ada@example.com"}"#;
+        assert_eq!(app.send(Method::POST, "/contacts", Some(contact)).await.status, StatusCode::CREATED);
+
+        let large = format!(r#"{{"firstname":"{}","lastname":"Lovelace","phone":"","email":""}}"#, "Ada".repeat(30));
+        let resp = app.send(Method::POST, "/contacts", Some(&large)).await;
+        assert_eq!(resp.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(resp.json()["detail"], "the request body is larger than 80 bytes");
+
+        // without a Content-Length, the body is cut off as it is read
+        let chunks: Vec<Result<String, std::io::Error>> = vec![Ok("lastname\n".to_string()), Ok("Doe\n".repeat(30))];
+        let req = Request::post("/contacts/import?format=csv").body(Body::wrap_stream(futures::stream::iter(chunks))).unwrap();
+        assert_eq!(app.request(req).await.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(app.get("/contacts").await.json()["total"], 1)
+    }
+
+    #[tokio::test]
+    async fn import_reports_each_line() {
+        let app = TestApp::new();
+        let csv = "First Name,Last Name,Phone,E-mail\nAda,Lovelace,+33 6 12 34 56 78,ada@example.com\nAlan,Turing,,not-an-email\nGrace,,,\n";
+        let import = |uri: &'static str| app.request(Request::post(uri).header("content-type", "text/csv").body(Body::from(csv)).unwrap());
+
+        let report = import("/contacts/import?dry_run=true").await.json();
+        assert_eq!((report["dry_run"].as_bool(), report["created"].as_u64(), report["failed"].as_u64()), (Some(true), Some(1), Some(2)));
+        assert_eq!(app.get("/contacts").await.json()["total"], 0, "a dry run should not write");
+
+        let report = import("/contacts/import").await.json();
+        let lines: Vec<(u64, &str)> = report["results"].as_array().unwrap().iter().map(|r| (r["row"].as_u64().unwrap(), r["status"].as_str().unwrap())).collect();
+        assert_eq!(lines, vec![(2, "created"), (3, "failed"), (4, "failed")]);
+        assert_eq!(app.get("/contacts/1").await.json()["phone"], "+33612345678");
+
+        // a phone's UID happening to match a local id
+        let vcard = "BEGIN:VCARD\r\nVERSION:4.0\r\nUID:1\r\nN:Byron;Ada;;;\r\nEND:VCARD\r\n";
+        let report = app.send(Method::POST, "/contacts/import?format=vcf", Some(vcard)).await.json();
+        assert_eq!((report["created"].as_u64(), report["updated"].as_u64()), (Some(1), Some(0)));
+        assert_ne!(report["results"][0]["id"], 1);
+        assert_eq!(app.get("/contacts/1").await.json()["lastname"], "Lovelace", "a foreign UID should not overwrite a contact");
+        let report = app.send(Method::POST, "/contacts/import?format=csv", Some("id,lastname\n1,Hopper\n")).await.json();
+        assert_eq!((report["created"].as_u64(), report["updated"].as_u64()), (Some(1), Some(0)));
+        assert_eq!(app.get("/contacts/1").await.json()["lastname"], "Lovelace", "a foreign id column should not overwrite a contact");
+
+        let resp = app.send(Method::POST, "/contacts/import", Some("lastname\nDoe\n")).await;
+        assert_eq!(resp.status, StatusCode::BAD_REQUEST, "the format cannot be told from JSON");
+        let resp = app.send(Method::POST, "/contacts/import?format=csv", Some("name\nDoe\n")).await;
+        assert_eq!(resp.json()["detail"], "no column maps to lastname");
+        let resp = app.send(Method::POST, "/contacts/import?format=csv&mapping=name:lastname", Some("name\nDoe\n")).await;
+        assert_eq!(resp.json()["created"], 1);
+    }
+
+    #[tokio::test]
+    async fn invalid_id_is_bad_request() {
+        let resp = TestApp::new().get("/contacts/abc").await;
+        assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+        assert_eq!(resp.header("content-type"), Some("application/problem+json"));
+        assert_eq!(resp.json()["detail"], "invalid id 'abc'");
+    }
+
+    #[tokio::test]
+    async fn malformed_body_is_bad_request() {
+        let resp = TestApp::new().send(Method::POST, "/contacts", Some("{")).await;
+        assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn invalid_contact_is_unprocessable() {
+        let body = r#"{"firstname":"","lastname":"","phone":"12","email":"nope"}"#;
+        let resp = TestApp::new().send(Method::POST, "/contacts", Some(body)).await;
+        assert_eq!(resp.status, StatusCode::UNPROCESSABLE_ENTITY);
+        let fields: Vec<String> = resp.json()["errors"].as_array().unwrap().iter().map(|e| e["field"].as_str().unwrap().to_string()).collect();
+        assert_eq!(fields, vec!["lastname", "phone", "email"]);
+    }
+
+    #[tokio::test]
+    async fn missing_contact_is_not_found() {
+        let app = TestApp::new();
+        let body = r#"{"firstname":"","lastname":"Doe","phone":"","email":""}"#;
+        assert_eq!(app.send(Method::PUT, "/contacts/3", Some(body)).await.status, StatusCode::NOT_FOUND);
+        assert_eq!(app.send(Method::PATCH, "/contacts/3", Some("{}")).await.status, StatusCode::NOT_FOUND);
+        assert_eq!(app.send(Method::DELETE, "/contacts/3", None).await.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn delete_moves_to_trash() {
+        let app = TestApp::new();
+        app.send(Method::POST, "/contacts", Some(r#"{"firstname":"","lastname":"Doe","phone":"","email":""}"#)).await;
+        assert_eq!(app.send(Method::DELETE, "/contacts/1", None).await.status, StatusCode::NO_CONTENT);
+        assert_eq!(app.get("/contacts/1").await.status, StatusCode::NOT_FOUND);
+
+        let trash = app.get("/contacts/trash").await.json();
+        assert_eq!(trash["total"], 1);
+        assert_eq!(trash["items"][0]["lastname"], "Doe");
+        assert!(trash["items"][0]["deleted_at"].as_str().unwrap().ends_with('Z'));
+
+        let resp = app.send(Method::POST, "/contacts/1/restore", None).await;
+        assert_eq!(resp.status, StatusCode::OK);
+        assert_eq!(resp.header("etag"), Some("\"2\""));
+        assert_eq!(app.get("/contacts/1").await.status, StatusCode::OK);
+        assert_eq!(app.get("/contacts/trash").await.json()["total"], 0);
+        assert_eq!(app.send(Method::POST, "/contacts/1/restore", None).await.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn search_ranks_and_highlights() {
+        let app = TestApp::new();
+        for body in [
+            r#"{"firstname":"Ada","lastname":"Lovelace","phone":"","email":"ada@example.com"}"#,
+            r#"{"firstname":"Alan","lastname":"Turing","phone":"","email":"alan@example.com"}"#,
+            r#"{"firstname":"Love","lastname":"Smith","phone":"","email":""}"#,
+        ] {
+            app.send(Method::POST, "/contacts", Some(body)).await;
+        }
+        let resp = app.get("/contacts/search?q=lovelace").await;
+        assert_eq!(resp.status, StatusCode::OK);
+        let items = resp.json()["items"].as_array().unwrap().clone();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0]["id"], 1);
+        assert_eq!(items[0]["highlight"]["lastname"], "<mark>Lovelace</mark>");
+
+        let items = app.get("/contacts/search?q=love").await.json()["items"].as_array().unwrap().clone();
+        let ids: Vec<_> = items.iter().map(|i| i["id"].as_i64().unwrap()).collect();
+        assert_eq!(ids, vec![1, 3]);
+
+        assert_eq!(app.get("/contacts/search?q=lovlace").await.json()["items"][0]["id"], 1, "misspellings should match");
+        assert_eq!(app.get("/contacts/search?q=%20").await.status, StatusCode::BAD_REQUEST);
+        assert_eq!(app.get("/contacts/search").await.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn invalid_query_is_bad_request() {
+        let resp = TestApp::new().get("/contacts?sort=shoe_size").await;
+        assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+    }
 }
@@ -0,0 +1,123 @@
+use crate::repository::Repository;
+use crate::{Context, Response};
+use hyper::{header, StatusCode};
+use serde_json::{json, Value};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+/// Upper bound for dependency checks, so that a stuck database fails the
+/// probe instead of hanging it.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Liveness: the process is up and serving requests.
+pub async fn live(_ctx: Context) -> Response {
+    json_response(StatusCode::OK, json!({ "status": "up" }))
+}
+
+/// Readiness: the service can handle traffic, i.e. it is not shutting down
+/// and the database answers.
+pub async fn ready(ctx: Context) -> Response {
+    let shutting_down = ctx.state.shutting_down.load(Ordering::Relaxed);
+    let database = check_database(ctx.state.repository.as_ref()).await;
+    let database_up = database["status"] == "up";
+    let pool = ctx.state.repository.pool_status();
+
+    let ready = database_up && !shutting_down;
+    let body = json!({
+        "status": if ready { "ready" } else { "unavailable" },
+        "shutting_down": shutting_down,
+        "checks": {
+            "database": database,
+            "pool": pool.map(|pool| json!({
+                "connections": pool.connections,
+                "idle": pool.idle,
+                "max_size": pool.max_size,
+            })),
+        },
+    });
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    json_response(status, body)
+}
+
+async fn check_database(repository: &dyn Repository) -> Value {
+    let start = Instant::now();
+    let result = tokio::time::timeout(CHECK_TIMEOUT, async {
+        repository.ping().await?;
+        repository.schema_version().await
+    })
+    .await;
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+    match result {
+        Ok(Ok(version)) => json!({ "status": "up", "latency_ms": latency_ms, "migration_version": version }),
+        Ok(Err(e)) => json!({ "status": "down", "latency_ms": latency_ms, "error": e.to_string() }),
+        Err(_) => json!({ "status": "down", "latency_ms": latency_ms, "error": "timed out" }),
+    }
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response {
+    hyper::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(body.to_string().into())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::health::{live, ready};
+    use crate::repository::{test_dsn, InMemoryRepository, PgsqlRepository, PoolConfig};
+    use crate::testing::json_body;
+    use crate::{AppState, Context};
+    use hyper::{Body, Request, StatusCode};
+    use route_recognizer::Params;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const UNREACHABLE_DSN: &str = "host=127.0.0.1 port=1 user=test";
+
+    async fn state(dsn: &str) -> Arc<AppState> {
+        let config = PoolConfig { acquire_timeout: Duration::from_millis(200), ..PoolConfig::default() };
+        let repository = PgsqlRepository::with_config(dsn, &config).await.unwrap();
+        Arc::new(AppState::new(Arc::new(repository)))
+    }
+
+    #[tokio::test]
+    async fn live_is_always_up() {
+        let state = Arc::new(AppState::new(Arc::new(InMemoryRepository::default())));
+        let resp = live(Context::new(state, Request::new(Body::empty()), Params::new())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(json_body(resp).await["status"], "up");
+    }
+
+    #[tokio::test]
+    async fn ready_with_database() {
+        let resp = ready(Context::new(state(&test_dsn()).await, Request::new(Body::empty()), Params::new())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = json_body(resp).await;
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["checks"]["database"]["status"], "up");
+        assert_eq!(body["checks"]["pool"]["max_size"], 10);
+    }
+
+    #[tokio::test]
+    async fn not_ready_without_database() {
+        // nothing listens on port 1
+        let state = state(UNREACHABLE_DSN).await;
+        let resp = ready(Context::new(state, Request::new(Body::empty()), Params::new())).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json_body(resp).await["checks"]["database"]["status"], "down");
+    }
+
+    #[tokio::test]
+    async fn not_ready_while_shutting_down() {
+        let state = state(&test_dsn()).await;
+        state.shutting_down.store(true, Ordering::Relaxed);
+        let resp = ready(Context::new(state, Request::new(Body::empty()), Params::new())).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = json_body(resp).await;
+        assert_eq!(body["shutting_down"], true);
+        assert_eq!(body["checks"]["database"]["status"], "up");
+    }
+}
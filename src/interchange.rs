@@ -0,0 +1,357 @@
+//! File formats contacts are exported to and imported from: CSV for
+//! spreadsheets and vCard 4.0 (RFC 6350) for phones and address books.
+
+use crate::repository::Contact;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    #[default]
+    Csv,
+    Vcf,
+}
+
+/// Columns of an exported CSV file, in `Contact` field order.
+pub const CSV_COLUMNS: [&str; 6] = ["id", "firstname", "lastname", "phone", "email", "version"];
+
+/// A contact read from a file, or the reason it is invalid, with the line it starts on.
+pub type DecodedRow = (usize, Result<Contact, String>);
+
+/// vCard lines longer than this many bytes are folded.
+const VCARD_LINE_LENGTH: usize = 75;
+
+impl FileFormat {
+    pub fn from_content_type(content_type: &str) -> Option<FileFormat> {
+        match content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase().as_str() {
+            "text/csv" => Some(FileFormat::Csv),
+            "text/vcard" | "text/x-vcard" => Some(FileFormat::Vcf),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            FileFormat::Csv => "text/csv; charset=utf-8",
+            FileFormat::Vcf => "text/vcard; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            FileFormat::Csv => "csv",
+            FileFormat::Vcf => "vcf",
+        }
+    }
+
+    /// Encodes one page of an export. The CSV header goes with the first page.
+    pub fn encode(self, contacts: &[Contact], first: bool) -> Vec<u8> {
+        match self {
+            FileFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+                if first {
+                    writer.write_record(CSV_COLUMNS).expect("writing to a Vec cannot fail");
+                }
+                for contact in contacts {
+                    writer.serialize(contact).expect("a contact always serializes to a record");
+                }
+                writer.into_inner().expect("writing to a Vec cannot fail")
+            }
+            FileFormat::Vcf => contacts.iter().map(vcard).collect::<String>().into_bytes(),
+        }
+    }
+
+    /// Decodes an uploaded file into contacts, without ids: an `id` column or
+    /// a `UID` comes from whatever system wrote the file, so that taking it as
+    /// ours would overwrite unrelated contacts. Fails as a whole when the file
+    /// cannot be read at all.
+    pub fn decode(self, input: &[u8], mapping: &Mapping) -> Result<Vec<DecodedRow>, String> {
+        match self {
+            FileFormat::Csv => decode_csv(input, mapping),
+            FileFormat::Vcf => decode_vcards(std::str::from_utf8(input).map_err(|e| format!("invalid UTF-8: {}", e))?),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    Firstname,
+    Lastname,
+    Phone,
+    Email,
+}
+
+impl Field {
+    /// Recognizes the usual spellings of a column name.
+    fn from_name(name: &str) -> Option<Field> {
+        match normalize(name).as_str() {
+            "firstname" | "first" | "givenname" => Some(Field::Firstname),
+            "lastname" | "last" | "surname" | "familyname" => Some(Field::Lastname),
+            "phone" | "phonenumber" | "telephone" | "tel" | "mobile" => Some(Field::Phone),
+            "email" | "emailaddress" | "mail" => Some(Field::Email),
+            _ => None,
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+/// CSV columns mapped to contact fields by the client, such as
+/// `Given Name:firstname,Company Phone:phone`, on top of the recognized names.
+#[derive(Default, Debug)]
+pub struct Mapping(HashMap<String, Field>);
+
+impl Mapping {
+    pub fn parse(mapping: &str) -> Result<Mapping, String> {
+        let mut columns = HashMap::new();
+        for pair in mapping.split(',').filter(|p| !p.trim().is_empty()) {
+            let (column, field) = pair.rsplit_once(':').ok_or_else(|| format!("invalid mapping '{}', expected column:field", pair))?;
+            let field = Field::from_name(field).ok_or_else(|| format!("unknown field '{}' in mapping", field))?;
+            columns.insert(normalize(column), field);
+        }
+        Ok(Mapping(columns))
+    }
+
+    fn field(&self, column: &str) -> Option<Field> {
+        self.0.get(&normalize(column)).copied().or_else(|| Field::from_name(column))
+    }
+}
+
+fn decode_csv(input: &[u8], mapping: &Mapping) -> Result<Vec<DecodedRow>, String> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).trim(csv::Trim::All).from_reader(input);
+    let columns: Vec<Option<Field>> = reader.headers().map_err(|e| e.to_string())?.iter().map(|h| mapping.field(h)).collect();
+    if !columns.contains(&Some(Field::Lastname)) {
+        return Err("no column maps to lastname".to_string());
+    }
+    Ok(reader
+        .records()
+        .map(|record| match record {
+            Ok(record) => {
+                let line = record.position().map_or(0, |p| p.line() as usize);
+                let mut fields = columns.iter().zip(record.iter()).filter_map(|(field, value)| field.map(|f| (f, value)));
+                (line, Ok(contact_from_fields(&mut fields)))
+            }
+            Err(err) => (err.position().map_or(0, |p| p.line() as usize), Err(err.to_string())),
+        })
+        .collect())
+}
+
+fn contact_from_fields(fields: &mut dyn Iterator<Item = (Field, &str)>) -> Contact {
+    let mut contact = Contact { id: 0, firstname: String::new(), lastname: String::new(), phone: String::new(), email: String::new(), version: 0 };
+    for (field, value) in fields {
+        match field {
+            Field::Firstname => contact.firstname = value.to_string(),
+            Field::Lastname => contact.lastname = value.to_string(),
+            Field::Phone => contact.phone = normalize_phone(value),
+            Field::Email => contact.email = value.to_string(),
+        }
+    }
+    contact
+}
+
+/// Drops the separators people write phone numbers with, so that
+/// `+33 6 12-34-56-78` passes as E.164.
+fn normalize_phone(phone: &str) -> String {
+    phone.chars().filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')')).collect()
+}
+
+fn vcard(contact: &Contact) -> String {
+    let mut lines = vec![
+        "BEGIN:VCARD".to_string(),
+        "VERSION:4.0".to_string(),
+        format!("UID:{}", contact.id),
+        format!("FN:{}", escape(format!("{} {}", contact.firstname, contact.lastname).trim())),
+        format!("N:{};{};;;", escape(&contact.lastname), escape(&contact.firstname)),
+    ];
+    if !contact.phone.is_empty() {
+        lines.push(format!("TEL;VALUE=uri:tel:{}", contact.phone));
+    }
+    if !contact.email.is_empty() {
+        lines.push(format!("EMAIL:{}", escape(&contact.email)));
+    }
+    lines.push("END:VCARD".to_string());
+    lines.iter().map(|line| fold(line) + "\r\n").collect()
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(',', "\\,").replace(';', "\\;").replace('\n', "\\n")
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => out.push('\n'),
+            Some(escaped) => out.push(escaped),
+            None => {}
+        }
+    }
+    out
+}
+
+/// Splits a structured value, such as `N`, on separators that are not escaped.
+fn split_components(value: &str) -> Vec<String> {
+    let mut components = vec![String::new()];
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            ';' if !escaped => components.push(String::new()),
+            _ => {
+                escaped = c == '\\' && !escaped;
+                components.last_mut().unwrap().push(c);
+            }
+        }
+    }
+    components.iter().map(|c| unescape(c)).collect()
+}
+
+/// Breaks a line into continuation lines starting with a space.
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len());
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > VCARD_LINE_LENGTH {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded
+}
+
+fn decode_vcards(input: &str) -> Result<Vec<DecodedRow>, String> {
+    // unfold continuation lines, keeping the number of the line each one started on
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        match (line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')), lines.last_mut()) {
+            (Some(rest), Some((_, previous))) => previous.push_str(rest),
+            _ => lines.push((i + 1, line.to_string())),
+        }
+    }
+
+    let mut cards = Vec::new();
+    let mut card: Option<(usize, Vec<(String, String)>)> = None;
+    for (line, content) in lines {
+        let (name, value) = match content.split_once(':') {
+            Some((name, value)) => (name.split(';').next().unwrap_or("").to_ascii_uppercase(), value.to_string()),
+            None => continue,
+        };
+        // drop the group prefix of names such as `item1.TEL`
+        let name = name.rsplit('.').next().unwrap_or("").to_string();
+        match (name.as_str(), value.trim().eq_ignore_ascii_case("VCARD")) {
+            ("BEGIN", true) => {
+                if let Some((start, _)) = card.replace((line, Vec::new())) {
+                    cards.push((start, Err("missing END:VCARD".to_string())));
+                }
+            }
+            ("END", true) => {
+                if let Some((start, properties)) = card.take() {
+                    cards.push((start, Ok(contact_from_vcard(&properties))));
+                }
+            }
+            _ => {
+                if let Some((_, properties)) = card.as_mut() {
+                    properties.push((name, value));
+                }
+            }
+        }
+    }
+    if let Some((start, _)) = card {
+        cards.push((start, Err("missing END:VCARD".to_string())));
+    }
+    Ok(cards)
+}
+
+fn contact_from_vcard(properties: &[(String, String)]) -> Contact {
+    let property = |name: &str| properties.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+    let name = property("N").map(split_components).unwrap_or_default();
+    let mut lastname = name.first().cloned().unwrap_or_default();
+    if lastname.is_empty() {
+        lastname = property("FN").map(unescape).unwrap_or_default();
+    }
+    Contact {
+        // a UID names the card in the address book it comes from, even ours
+        // once exported and edited elsewhere, so it never picks a contact
+        id: 0,
+        firstname: name.get(1).cloned().unwrap_or_default(),
+        lastname,
+        phone: property("TEL").map(|tel| normalize_phone(tel.trim().trim_start_matches("tel:"))).unwrap_or_default(),
+        email: property("EMAIL").map(unescape).unwrap_or_default(),
+        version: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interchange::{DecodedRow, FileFormat, Mapping};
+    use crate::repository::Contact;
+    use crate::testing::contact;
+
+    fn ada(lastname: &str) -> Contact {
+        Contact { firstname: "Ada".to_string(), ..contact(lastname, "+33612345678", "ada@example.com") }
+    }
+
+    fn decoded(format: FileFormat, input: &str, mapping: &str) -> Vec<DecodedRow> {
+        format.decode(input.as_bytes(), &Mapping::parse(mapping).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let contacts = [Contact { id: 1, ..ada("Lovelace, Countess") }, Contact { id: 2, ..contact("Turing", "", "") }];
+        let mut exported = FileFormat::Csv.encode(&contacts[..1], true);
+        exported.extend(FileFormat::Csv.encode(&contacts[1..], false));
+        let exported = String::from_utf8(exported).unwrap();
+        assert!(exported.starts_with("id,firstname,lastname,phone,email,version\n1,Ada,\"Lovelace, Countess\""));
+        let imported: Vec<Contact> = decoded(FileFormat::Csv, &exported, "").into_iter().map(|(_, c)| c.unwrap()).collect();
+        let new = |c: &Contact| Contact { id: 0, ..c.clone() };
+        assert_eq!(imported, contacts.iter().map(new).collect::<Vec<_>>(), "the id column should not be taken as ids");
+    }
+
+    #[test]
+    fn csv_maps_headers() {
+        let input = "Given Name,Surname,Mobile,Company\nAda,Lovelace,+33 6 12-34-56-78,ACME\nAlan\n";
+        let rows = decoded(FileFormat::Csv, input, "");
+        assert_eq!(rows[0], (2, Ok(Contact { email: String::new(), ..ada("Lovelace") })));
+        assert_eq!(rows[1], (3, Ok(Contact { firstname: "Alan".to_string(), ..contact("", "", "") })), "short rows leave fields empty for validation");
+
+        let rows = decoded(FileFormat::Csv, "Name,Work Mail,ID\nLovelace,ada@example.com,x\n", "name:lastname,work mail:email");
+        assert_eq!(rows[0], (2, Ok(contact("Lovelace", "", "ada@example.com"))), "foreign ids should be ignored");
+        let rows = decoded(FileFormat::Csv, "Name,Work Mail\nLovelace,ada@example.com\n", "name:lastname,work mail:email");
+        assert_eq!(rows[0].1, Ok(contact("Lovelace", "", "ada@example.com")));
+
+        assert!(FileFormat::Csv.decode(b"Name,Mail\n", &Mapping::default()).is_err(), "lastname column is required");
+        assert!(Mapping::parse("name:company").is_err());
+    }
+
+    #[test]
+    fn vcard_round_trip() {
+        let contacts = vec![
+            Contact { id: 1, ..ada("Lovelace; Byron") },
+            Contact { id: 2, ..contact(&"Long".repeat(30), "", "") },
+        ];
+        let exported = String::from_utf8(FileFormat::Vcf.encode(&contacts, true)).unwrap();
+        assert!(exported.starts_with("BEGIN:VCARD\r\nVERSION:4.0\r\nUID:1\r\nFN:Ada Lovelace\\; Byron\r\nN:Lovelace\\; Byron;Ada;;;\r\n"));
+        assert!(exported.lines().all(|line| line.len() <= 75), "long lines should be folded");
+        let imported: Vec<(usize, Contact)> = decoded(FileFormat::Vcf, &exported, "").into_iter().map(|(l, c)| (l, c.unwrap())).collect();
+        let new = |c: &Contact| Contact { id: 0, ..c.clone() };
+        assert_eq!(imported, vec![(1, new(&contacts[0])), (9, new(&contacts[1]))], "UIDs should not be taken as ids");
+    }
+
+    #[test]
+    fn vcard_from_address_books() {
+        let input = "BEGIN:VCARD\nVERSION:3.0\nUID:urn:uuid:4fbe8971\nFN:Grace Hopper\nitem1.TEL;TYPE=cell:+1 (415) 555-0100\n\
+                     EMAIL;TYPE=work:grace@navy.mil\nEND:VCARD\nBEGIN:VCARD\nN:Turing;Alan\n";
+        let rows = decoded(FileFormat::Vcf, input, "");
+        assert_eq!(rows[0], (1, Ok(contact("Grace Hopper", "+14155550100", "grace@navy.mil"))));
+        assert_eq!(rows[1], (8, Err("missing END:VCARD".to_string())));
+    }
+}
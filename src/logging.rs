@@ -0,0 +1,266 @@
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("unknown log level '{}' (expected error, warn, info, debug or trace)", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Json = 1,
+    Logfmt,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "logfmt" => Ok(Format::Logfmt),
+            _ => Err(format!("unknown log format '{}' (expected json or logfmt)", s)),
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static FORMAT: AtomicU8 = AtomicU8::new(Format::Json as u8);
+
+pub fn init(level: Level, format: Format) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Logs a structured event: `log!(Level::Info, "message", key = value, ...)`.
+///
+/// Values can be anything `serde_json::json!` accepts. Nothing is evaluated
+/// when the level is filtered out.
+macro_rules! log {
+    ($level:expr, $msg:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        if $crate::logging::enabled($level) {
+            $crate::logging::write($level, $msg, &[$((stringify!($key), serde_json::json!($value))),*]);
+        }
+    };
+}
+pub(crate) use log;
+
+pub fn write(level: Level, msg: &str, fields: &[(&str, Value)]) {
+    let format = if FORMAT.load(Ordering::Relaxed) == Format::Logfmt as u8 { Format::Logfmt } else { Format::Json };
+    let line = Line { ts: rfc3339(SystemTime::now()), level, msg, fields, format };
+    write_line(&mut output(), &line);
+}
+
+/// Unlike `println!`, a closed or full output loses the line instead of panicking.
+fn write_line(out: &mut dyn Write, line: &Line) {
+    let _ = writeln!(out, "{}", line);
+}
+
+#[cfg(not(test))]
+fn output() -> impl Write {
+    std::io::stdout().lock()
+}
+
+/// Under test, lines go through `print!`, which the test harness captures.
+#[cfg(test)]
+fn output() -> impl Write {
+    struct Captured;
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            print!("{}", String::from_utf8_lossy(buf));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    Captured
+}
+
+/// Keys of every line, which fields are not allowed to replace.
+const BUILT_IN_KEYS: [&str; 3] = ["ts", "level", "msg"];
+
+/// The key of a field, prefixed with `field.` when it would collide with a
+/// built-in key.
+fn field_key(key: &str) -> Cow<'_, str> {
+    if BUILT_IN_KEYS.contains(&key) {
+        Cow::Owned(format!("field.{}", key))
+    } else {
+        Cow::Borrowed(key)
+    }
+}
+
+struct Line<'a> {
+    ts: String,
+    level: Level,
+    msg: &'a str,
+    fields: &'a [(&'a str, Value)],
+    format: Format,
+}
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.format {
+            Format::Json => {
+                let mut object = Map::new();
+                object.insert("ts".to_string(), Value::from(self.ts.as_str()));
+                object.insert("level".to_string(), Value::from(self.level.as_str()));
+                object.insert("msg".to_string(), Value::from(self.msg));
+                for (key, value) in self.fields {
+                    object.insert(field_key(key).into_owned(), value.clone());
+                }
+                write!(f, "{}", Value::Object(object))
+            }
+            Format::Logfmt => {
+                write!(f, "ts={} level={} msg={}", self.ts, self.level.as_str(), logfmt_value(&Value::from(self.msg)))?;
+                for (key, value) in self.fields {
+                    write!(f, " {}={}", field_key(key), logfmt_value(value))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn logfmt_value(value: &Value) -> String {
+    let raw = match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    };
+    if raw.is_empty() || raw.contains(|c: char| c == ' ' || c == '"' || c == '=' || c.is_control()) {
+        Value::from(raw).to_string()
+    } else {
+        raw
+    }
+}
+
+/// Formats a UTC timestamp like `2021-03-04T05:06:07.089Z`.
+pub fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // civil-from-days, from Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60, since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::{rfc3339, write_line, Format, Level, Line};
+    use serde_json::json;
+    use std::io::{ErrorKind, Write};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// A stdout whose reader went away.
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_millis(951_782_400_123)), "2000-02-29T00:00:00.123Z");
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_secs(1_792_353_694)), "2026-10-18T20:01:34.000Z");
+    }
+
+    #[test]
+    fn formats_lines() {
+        let fields = [("method", json!("GET")), ("status", json!(200)), ("route", json!(null)), ("ua", json!("curl 7.0"))];
+        let line = |format| Line { ts: "T".to_string(), level: Level::Info, msg: "access", fields: &fields, format }.to_string();
+        assert_eq!(line(Format::Logfmt), r#"ts=T level=info msg=access method=GET status=200 route="" ua="curl 7.0""#);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&line(Format::Json)).unwrap(),
+            json!({"ts": "T", "level": "info", "msg": "access", "method": "GET", "status": 200, "route": null, "ua": "curl 7.0"})
+        );
+    }
+
+    #[test]
+    fn prefixes_fields_named_like_built_in_keys() {
+        let fields = [("msg", json!("spoofed")), ("level", json!("error")), ("status", json!(200))];
+        let line = |format| Line { ts: "T".to_string(), level: Level::Info, msg: "access", fields: &fields, format }.to_string();
+        assert_eq!(line(Format::Logfmt), "ts=T level=info msg=access field.msg=spoofed field.level=error status=200");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&line(Format::Json)).unwrap(),
+            json!({"ts": "T", "level": "info", "msg": "access", "field.msg": "spoofed", "field.level": "error", "status": 200})
+        );
+    }
+
+    #[test]
+    fn write_errors_lose_the_line() {
+        let line = Line { ts: "T".to_string(), level: Level::Info, msg: "access", fields: &[], format: Format::Logfmt };
+        write_line(&mut ClosedPipe, &line);
+        let mut written = Vec::new();
+        write_line(&mut written, &line);
+        assert_eq!(written, b"ts=T level=info msg=access\n");
+    }
+
+    #[test]
+    fn parses_levels() {
+        assert_eq!("WARN".parse::<Level>(), Ok(Level::Warn));
+        assert!("verbose".parse::<Level>().is_err());
+        assert!(Level::Error < Level::Debug);
+    }
+}
@@ -1,62 +1,198 @@
 use bytes::Bytes;
 use hyper::{
-    body::to_bytes,
+    body::HttpBody,
+    header::CONTENT_LENGTH,
+    server::conn::AddrStream,
     service::{make_service_fn, service_fn},
-    Body, Request, Server
+    Body, Method, Request, Server
 };
 use route_recognizer::Params;
-use router::Router;
+use router::{AllowedMethods, RoutePattern, Router};
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
-use crate::repository::{PgsqlRepository, Repository};
-use futures::executor::block_on;
+use crate::config::{Command, Config, DEFAULT_MAX_BODY_BYTES};
+use crate::error::ApiError;
+use crate::logging::{log, Level};
+use crate::metrics::HttpMetrics;
+use crate::middleware::{AccessLog, Next, ServerTiming};
+use crate::repository::Repository;
+use crate::validation::Validate;
 
+mod bulk;
+mod config;
+mod error;
+mod extract;
 mod handler;
+mod health;
+mod interchange;
+mod logging;
+mod metrics;
+mod middleware;
+mod migration;
+mod negotiate;
+mod purge;
+mod query;
 mod router;
 mod repository;
+mod search;
+mod shutdown;
+#[cfg(test)]
+mod testing;
+mod validation;
 
 type Response = hyper::Response<hyper::Body>;
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
 pub struct AppState {
-    pub repository: Arc<PgsqlRepository>
+    pub repository: Arc<dyn Repository + Send + Sync>,
+    /// Set once a shutdown signal is received, to fail readiness probes while draining.
+    pub shutting_down: AtomicBool,
+    /// Largest request body read into memory, larger ones being answered with 413.
+    pub max_body_bytes: usize,
+}
+
+impl AppState {
+    pub fn new(repository: Arc<dyn Repository + Send + Sync>) -> AppState {
+        AppState { repository, shutting_down: AtomicBool::new(false), max_body_bytes: DEFAULT_MAX_BODY_BYTES }
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let mut router: Router = Router::new();
-    router.get("/contacts/:id", Box::new(handler::get_contact));
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    logging::init(config.log_level, config.log_format);
+
+    let repository = match repository::connect(&config.database_url, &config.pool).await {
+        Ok(repository) => repository,
+        Err(e) => {
+            log!(Level::Error, "cannot connect to database", error = e.to_string());
+            std::process::exit(1);
+        }
+    };
+
+    let result = match config.command {
+        Command::Serve => serve(config, repository).await,
+        Command::MigrateUp => repository.migrate_up().await.map(|versions| {
+            println!("applied {} migration(s) {:?}", versions.len(), versions);
+        }),
+        Command::MigrateDown => repository.migrate_down().await.map(|version| match version {
+            Some(version) => println!("reverted migration {}", version),
+            None => println!("no migration to revert"),
+        }),
+        Command::MigrateStatus => repository.migration_status().await.map(|statuses| {
+            for status in statuses {
+                println!("{}", status.describe());
+            }
+        }),
+    };
+    if let Err(e) = result {
+        log!(Level::Error, "command failed", error = e.to_string());
+        std::process::exit(1);
+    }
+}
+
+async fn serve(config: Config, repository: Arc<dyn Repository + Send + Sync>) -> Result<(), repository::Error> {
+    if config.auto_migrate {
+        repository.migrate_up().await?;
+    }
 
-    let shared_router = Arc::new(router);
-    let new_service = make_service_fn(move |_| {
-        let app_state = Arc::new(AppState {
-            repository: Arc::new(block_on(PgsqlRepository::new("host=postgresql user=classe password=classe dbname=classe")))
-        });
+    let purge = purge::spawn(repository.clone(), config.trash_retention);
+    let app_state = Arc::new(AppState { max_body_bytes: config.max_body_bytes, ..AppState::new(repository) });
 
+    let shared_router = Arc::new(router());
+    let service_state = app_state.clone();
+    let new_service = make_service_fn(move |conn: &AddrStream| {
+        let app_state = service_state.clone();
         let router_capture = shared_router.clone();
-        async {
+        let peer = conn.remote_addr();
+        async move {
             Ok::<_, Error>(service_fn(move |req| {
-                route(router_capture.clone(), req, Arc::clone(&app_state))
+                route(router_capture.clone(), req, Arc::clone(&app_state), peer)
             }))
         }
     });
 
-    let addr = "0.0.0.0:8080".parse().expect("address creation works");
+    let addr = config.bind;
     let server = Server::bind(&addr).serve(new_service);
-    println!("Listening on http://{}", addr);
-    let _ = server.await;
+    log!(Level::Info, "listening", address = format!("http://{}", addr));
+    let drained = shutdown::serve_with_drain(
+        |drain| server.with_graceful_shutdown(async { let _ = drain.await; }),
+        async {
+            shutdown::signal().await;
+            shutdown::fail_readiness(&app_state.shutting_down, config.readiness_grace).await;
+        },
+        config.drain_timeout,
+    ).await;
+    if !drained.complete {
+        log!(Level::Warn, "drain timeout elapsed, exiting with requests still in flight");
+    }
+
+    purge.abort();
+    // a request still running holds its pooled connection, so closing may never finish
+    let remaining = drained.deadline.saturating_duration_since(std::time::Instant::now());
+    if tokio::time::timeout(remaining, app_state.repository.close()).await.is_err() {
+        log!(Level::Warn, "drain timeout elapsed, exiting without closing every database connection");
+    }
+    log!(Level::Info, "shutdown complete");
+    Ok(())
+}
+
+/// The routes and global middleware of the service.
+fn router() -> Router {
+    let mut router: Router = Router::new();
+    router.middleware(AccessLog);
+    router.middleware(HttpMetrics);
+    router.middleware(ServerTiming);
+    router.get("/metrics", metrics::handler);
+    router.get("/health/live", health::live);
+    router.get("/health/ready", health::ready);
+    router.scope("/contacts", |contacts| {
+        contacts.get("/", handler::list_contacts);
+        contacts.post("/", handler::create_contact);
+        contacts.post("/bulk", handler::bulk_contacts);
+        contacts.get("/export", handler::export_contacts);
+        contacts.post("/import", handler::import_contacts);
+        contacts.get("/search", handler::search_contacts);
+        contacts.get("/trash", handler::list_trash);
+        contacts.get("/:id", handler::get_contact);
+        contacts.put("/:id", handler::update_contact);
+        contacts.patch("/:id", handler::patch_contact);
+        contacts.delete("/:id", handler::delete_contact);
+        contacts.post("/:id/restore", handler::restore_contact);
+    });
+    router
 }
 
+/// The address of the client that opened the connection, stored in the request extensions.
+#[derive(Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
 async fn route(
     router: Arc<Router>,
-    req: Request<hyper::Body>,
+    mut req: Request<hyper::Body>,
     app_state: Arc<AppState>,
+    peer: SocketAddr,
 ) -> Result<Response, Error> {
-    let found_handler = router.route(req.uri().path(), req.method());
-    let resp = found_handler
-        .handler
-        .invoke(Context::new(app_state, req, found_handler.params))
+    let found_handler = router.find(req.uri().path(), req.method());
+    let head = req.method() == Method::HEAD;
+    req.extensions_mut().insert(PeerAddr(peer));
+    if let Some(pattern) = found_handler.pattern {
+        req.extensions_mut().insert(RoutePattern(pattern.to_string()));
+    }
+    if !found_handler.allowed.is_empty() {
+        req.extensions_mut().insert(AllowedMethods(found_handler.allowed));
+    }
+    let resp = Next::new(found_handler.handler, found_handler.middleware)
+        .run(Context::new(app_state, req, found_handler.params))
         .await;
-    Ok(resp)
+    Ok(if head { router::strip_body(resp) } else { resp })
 }
 
 pub struct Context {
@@ -76,15 +212,45 @@ impl Context {
         }
     }
 
-    pub async fn body_json<T: serde::de::DeserializeOwned>(&mut self) -> Result<T, Error> {
-        let body_bytes = match self.body_bytes {
+    pub fn query<T: serde::de::DeserializeOwned>(&self) -> Result<T, Error> {
+        Ok(serde_urlencoded::from_str(self.req.uri().query().unwrap_or(""))?)
+    }
+
+    /// The whole request body, read once and kept for later calls. Fails with
+    /// `ApiError::PayloadTooLarge` as soon as it outgrows `max_body_bytes`.
+    pub async fn body_bytes(&mut self) -> Result<&Bytes, ApiError> {
+        Ok(match self.body_bytes {
             Some(ref v) => v,
             _ => {
-                let body = to_bytes(self.req.body_mut()).await?;
-                self.body_bytes = Some(body);
+                let limit = self.state.max_body_bytes;
+                let too_large = ApiError::PayloadTooLarge(limit);
+                let length = self.req.headers().get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+                if length.is_some_and(|length| length > limit as u64) {
+                    return Err(too_large);
+                }
+                let mut body = Vec::new();
+                while let Some(chunk) = self.req.body_mut().data().await {
+                    let chunk = chunk.map_err(|e| ApiError::BadRequest(e.to_string()))?;
+                    if body.len() + chunk.len() > limit {
+                        return Err(too_large);
+                    }
+                    body.extend_from_slice(&chunk);
+                }
+                self.body_bytes = Some(body.into());
                 self.body_bytes.as_ref().expect("body_bytes was set above")
             }
-        };
-        Ok(serde_json::from_slice(&body_bytes)?)
+        })
+    }
+
+    pub async fn body_json<T: serde::de::DeserializeOwned>(&mut self) -> Result<T, ApiError> {
+        serde_json::from_slice(self.body_bytes().await?).map_err(|e| ApiError::BadRequest(e.to_string()))
+    }
+
+    /// Deserializes the JSON body and runs its validation rules, so that
+    /// handlers only ever see well-formed values.
+    pub async fn valid_json<T: serde::de::DeserializeOwned + Validate>(&mut self) -> Result<T, ApiError> {
+        let value: T = self.body_json().await?;
+        value.validate()?;
+        Ok(value)
     }
 }
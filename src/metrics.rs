@@ -0,0 +1,195 @@
+use crate::middleware::{Middleware, Next};
+use crate::repository::Error as RepositoryError;
+use crate::router::RoutePattern;
+use crate::{Context, Response};
+use async_trait::async_trait;
+use hyper::header;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// Process-wide Prometheus collectors, exposed on `GET /metrics`.
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+    pub http_requests_in_flight: IntGauge,
+    pub db_query_duration: HistogramVec,
+    pub db_errors: IntCounterVec,
+    pub db_pool_connections: IntGaugeVec,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_requests_in_flight =
+            IntGauge::new("http_requests_in_flight", "HTTP requests currently being handled").unwrap();
+        let db_query_duration = HistogramVec::new(
+            HistogramOpts::new("db_query_duration_seconds", "Database statement latency"),
+            &["operation"],
+        )
+        .unwrap();
+        let db_errors = IntCounterVec::new(Opts::new("db_errors_total", "Failed database queries and connections"), &["kind"]).unwrap();
+        let db_pool_connections = IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Database pool connections"),
+            &["state"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_request_duration.clone())).unwrap();
+        registry.register(Box::new(http_requests_in_flight.clone())).unwrap();
+        registry.register(Box::new(db_query_duration.clone())).unwrap();
+        registry.register(Box::new(db_errors.clone())).unwrap();
+        registry.register(Box::new(db_pool_connections.clone())).unwrap();
+
+        Metrics {
+            registry,
+            http_requests,
+            http_request_duration,
+            http_requests_in_flight,
+            db_query_duration,
+            db_errors,
+            db_pool_connections,
+        }
+    }
+
+    pub fn count_db_error(&self, err: &RepositoryError) {
+        self.db_errors.with_label_values(&[err.kind()]).inc()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics can be encoded");
+        buffer
+    }
+}
+
+/// Label used for requests that matched no route, to keep cardinality bounded.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Counts and times every request by method, route pattern and status.
+pub struct HttpMetrics;
+
+struct InFlight;
+
+impl InFlight {
+    fn start() -> InFlight {
+        metrics().http_requests_in_flight.inc();
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        metrics().http_requests_in_flight.dec();
+    }
+}
+
+#[async_trait]
+impl Middleware for HttpMetrics {
+    async fn handle(&self, ctx: Context, next: Next<'_>) -> Response {
+        let _in_flight = InFlight::start();
+        let start = Instant::now();
+        let method = ctx.req.method().to_string();
+        let route = ctx
+            .req
+            .extensions()
+            .get::<RoutePattern>()
+            .map(|p| p.0.clone())
+            .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+
+        let resp = next.run(ctx).await;
+
+        let status = resp.status().as_u16().to_string();
+        let labels = [method.as_str(), route.as_str(), status.as_str()];
+        metrics().http_requests.with_label_values(&labels).inc();
+        metrics()
+            .http_request_duration
+            .with_label_values(&labels)
+            .observe(start.elapsed().as_secs_f64());
+        resp
+    }
+}
+
+pub async fn handler(ctx: Context) -> Response {
+    if let Some(pool) = ctx.state.repository.pool_status() {
+        metrics().db_pool_connections.with_label_values(&["idle"]).set(pool.idle.into());
+        metrics()
+            .db_pool_connections
+            .with_label_values(&["in_use"])
+            .set((pool.connections - pool.idle).into());
+        metrics().db_pool_connections.with_label_values(&["max"]).set(pool.max_size.into());
+    }
+
+    hyper::Response::builder()
+        .header(header::CONTENT_TYPE, TextEncoder::new().format_type())
+        .body(metrics().encode().into())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::{handler, metrics, HttpMetrics};
+    use crate::middleware::Stack;
+    use crate::repository::{Error as RepositoryError, PgsqlRepository};
+    use crate::router::{Handler, RoutePattern};
+    use crate::{AppState, Context};
+    use hyper::{Body, Request};
+    use route_recognizer::Params;
+    use std::sync::Arc;
+
+    async fn context(req: Request<Body>) -> Context {
+        // the pool connects lazily, no database is reached by these tests
+        let repository = PgsqlRepository::with_config("host=localhost", &Default::default()).await.unwrap();
+        Context::new(Arc::new(AppState::new(Arc::new(repository))), req, Params::new())
+    }
+
+    async fn teapot(_ctx: Context) -> crate::Response {
+        hyper::Response::builder().status(418).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn counts_requests_by_route_pattern() {
+        let labels = ["DELETE", "/metrics-test/:id", "418"];
+        let before = metrics().http_requests.with_label_values(&labels).get();
+
+        let mut req = Request::builder().method("DELETE").uri("/metrics-test/1").body(Body::empty()).unwrap();
+        req.extensions_mut().insert(RoutePattern("/metrics-test/:id".to_string()));
+        Stack::new(teapot).with(HttpMetrics).invoke(context(req).await).await;
+
+        assert_eq!(metrics().http_requests.with_label_values(&labels).get(), before + 1);
+        assert_eq!(metrics().http_request_duration.with_label_values(&labels).get_sample_count(), before + 1);
+    }
+
+    #[tokio::test]
+    async fn exposes_text_format() {
+        metrics().count_db_error(&RepositoryError::Unavailable);
+        let resp = handler(context(Request::new(Body::empty())).await).await;
+        assert!(resp.headers()["content-type"].to_str().unwrap().starts_with("text/plain"));
+        let body = String::from_utf8(hyper::body::to_bytes(resp.into_body()).await.unwrap().to_vec()).unwrap();
+        assert!(body.contains("db_errors_total{kind=\"unavailable\"}"), "{}", body);
+        assert!(body.contains("db_pool_connections{state=\"max\"} 10"), "{}", body);
+        assert!(body.contains("http_requests_in_flight"), "{}", body);
+    }
+}
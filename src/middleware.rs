@@ -0,0 +1,225 @@
+use crate::logging::{log, Level};
+use crate::router::{Handler, RoutePattern};
+use crate::{Context, PeerAddr, Response};
+use async_trait::async_trait;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Shared logic wrapped around handlers.
+///
+/// A middleware receives the request `Context` and the rest of the chain as
+/// `next`. It can modify the context before calling `next.run(ctx)`,
+/// post-process the returned response, or short-circuit by returning a
+/// response without calling `next` at all.
+#[async_trait]
+pub trait Middleware: Send + Sync + 'static {
+    async fn handle(&self, ctx: Context, next: Next<'_>) -> Response;
+}
+
+/// The remainder of a middleware chain, ending with the handler.
+pub struct Next<'a> {
+    handler: &'a dyn Handler,
+    middleware: &'a [Arc<dyn Middleware>],
+}
+
+impl<'a> Next<'a> {
+    pub fn new(handler: &'a dyn Handler, middleware: &'a [Arc<dyn Middleware>]) -> Next<'a> {
+        Next { handler, middleware }
+    }
+
+    pub async fn run(mut self, ctx: Context) -> Response {
+        match self.middleware.split_first() {
+            Some((current, rest)) => {
+                self.middleware = rest;
+                current.handle(ctx, self).await
+            }
+            None => self.handler.invoke(ctx).await,
+        }
+    }
+}
+
+/// A handler with its own middleware, for logic that only applies to some routes.
+///
+/// Route middleware runs after the router's global middleware.
+pub struct Stack {
+    middleware: Vec<Arc<dyn Middleware>>,
+    handler: Arc<dyn Handler>,
+}
+
+impl Stack {
+    #[cfg(test)]
+    pub fn new(handler: impl Handler) -> Stack {
+        Stack { middleware: Vec::new(), handler: Arc::new(handler) }
+    }
+
+    /// Wraps the handler of a route mounted from a nested router in that router's middleware.
+    pub(crate) fn nested(middleware: Vec<Arc<dyn Middleware>>, handler: Arc<dyn Handler>) -> Stack {
+        Stack { middleware, handler }
+    }
+
+    #[cfg(test)]
+    pub fn with(mut self, middleware: impl Middleware) -> Stack {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+}
+
+#[async_trait]
+impl Handler for Stack {
+    async fn invoke(&self, ctx: Context) -> Response {
+        Next::new(&*self.handler, &self.middleware).run(ctx).await
+    }
+}
+
+/// Reports the time spent producing each response in a `Server-Timing` header.
+pub struct ServerTiming;
+
+#[async_trait]
+impl Middleware for ServerTiming {
+    async fn handle(&self, ctx: Context, next: Next<'_>) -> Response {
+        let start = Instant::now();
+        let mut resp = next.run(ctx).await;
+        let value = format!("app;dur={:.3}", start.elapsed().as_secs_f64() * 1000.0);
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            resp.headers_mut().append("server-timing", value);
+        }
+        resp
+    }
+}
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Writes one structured access log line per request.
+///
+/// Requests are identified by their `X-Request-Id` header, which is generated
+/// when missing so that handlers always see one, and echoed in the response.
+///
+/// Register it first so that its latency covers the other middleware.
+pub struct AccessLog;
+
+#[async_trait]
+impl Middleware for AccessLog {
+    async fn handle(&self, mut ctx: Context, next: Next<'_>) -> Response {
+        let start = Instant::now();
+        let request_id = ctx
+            .req
+            .headers()
+            .get(&REQUEST_ID)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty() && v.len() <= 128)
+            .map(String::from)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            ctx.req.headers_mut().insert(REQUEST_ID, value);
+        }
+
+        let method = ctx.req.method().to_string();
+        let path = ctx.req.uri().path().to_string();
+        let route = ctx.req.extensions().get::<RoutePattern>().map(|p| p.0.clone());
+        let peer = ctx.req.extensions().get::<PeerAddr>().map(|p| p.0.to_string());
+
+        let mut resp = next.run(ctx).await;
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            resp.headers_mut().insert(REQUEST_ID, value);
+        }
+        log!(
+            Level::Info,
+            "access",
+            method = method,
+            path = path,
+            route = route,
+            status = resp.status().as_u16(),
+            latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+            bytes = resp.body().size_hint().exact(),
+            request_id = request_id,
+            peer = peer,
+        );
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::middleware::{AccessLog, Middleware, Next, ServerTiming, Stack, REQUEST_ID};
+    use crate::repository::InMemoryRepository;
+    use crate::router::Handler;
+    use crate::{AppState, Context, Response};
+    use async_trait::async_trait;
+    use hyper::{Body, Request, StatusCode};
+    use route_recognizer::Params;
+    use std::sync::Arc;
+
+    async fn context(req: Request<Body>) -> Context {
+        Context::new(Arc::new(AppState::new(Arc::new(InMemoryRepository::default()))), req, Params::new())
+    }
+
+    struct Tag(&'static str);
+
+    #[async_trait]
+    impl Middleware for Tag {
+        async fn handle(&self, mut ctx: Context, next: Next<'_>) -> Response {
+            ctx.req.headers_mut().append("x-seen", self.0.parse().unwrap());
+            let mut resp = next.run(ctx).await;
+            resp.headers_mut().append("x-trace", self.0.parse().unwrap());
+            resp
+        }
+    }
+
+    struct Deny;
+
+    #[async_trait]
+    impl Middleware for Deny {
+        async fn handle(&self, _ctx: Context, _next: Next<'_>) -> Response {
+            hyper::Response::builder().status(StatusCode::FORBIDDEN).body(Body::empty()).unwrap()
+        }
+    }
+
+    async fn echo_seen(ctx: Context) -> String {
+        let seen: Vec<&str> = ctx.req.headers().get_all("x-seen").iter().map(|v| v.to_str().unwrap()).collect();
+        seen.join(",")
+    }
+
+    #[tokio::test]
+    async fn runs_in_order_around_handler() {
+        let stack = Stack::new(echo_seen).with(Tag("outer")).with(Tag("inner"));
+        let resp = stack.invoke(context(Request::new(Body::empty())).await).await;
+        let trace: Vec<&str> = resp.headers().get_all("x-trace").iter().map(|v| v.to_str().unwrap()).collect();
+        assert_eq!(trace, vec!["inner", "outer"]);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "outer,inner");
+    }
+
+    #[tokio::test]
+    async fn short_circuits() {
+        let stack = Stack::new(echo_seen).with(Tag("outer")).with(Deny).with(Tag("never"));
+        let resp = stack.invoke(context(Request::new(Body::empty())).await).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let trace: Vec<&str> = resp.headers().get_all("x-trace").iter().map(|v| v.to_str().unwrap()).collect();
+        assert_eq!(trace, vec!["outer"]);
+    }
+
+    #[tokio::test]
+    async fn server_timing_header() {
+        let middleware: Vec<Arc<dyn Middleware>> = vec![Arc::new(ServerTiming)];
+        let resp = Next::new(&echo_seen, &middleware).run(context(Request::new(Body::empty())).await).await;
+        assert!(resp.headers()["server-timing"].to_str().unwrap().starts_with("app;dur="));
+    }
+
+    async fn echo_request_id(ctx: Context) -> String {
+        ctx.req.headers()[REQUEST_ID].to_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn access_log_propagates_request_id() {
+        let stack = Stack::new(echo_request_id).with(AccessLog);
+        let req = Request::builder().header(REQUEST_ID, "abc-123").body(Body::empty()).unwrap();
+        let resp = stack.invoke(context(req).await).await;
+        assert_eq!(resp.headers()[REQUEST_ID], "abc-123");
+        assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), "abc-123");
+
+        let resp = stack.invoke(context(Request::new(Body::empty())).await).await;
+        assert_eq!(resp.headers()[REQUEST_ID].len(), 36, "a uuid should be generated");
+    }
+}
@@ -0,0 +1,236 @@
+use crate::logging::{log, rfc3339, Level};
+use crate::repository::Error;
+use std::time::SystemTime;
+use tokio_postgres::Client;
+
+pub mod sqlite;
+
+/// A versioned schema change, embedded into the binary from `migrations/`.
+/// SQLite runs its own variant of the statements, which is the same file
+/// when the SQL is portable.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+    pub sqlite_up: &'static str,
+    pub sqlite_down: &'static str,
+}
+
+/// Every migration, in version order. Add new files to `migrations/` and list them here.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_contact",
+        up: include_str!("../migrations/0001_create_contact.up.sql"),
+        down: include_str!("../migrations/0001_create_contact.down.sql"),
+        sqlite_up: include_str!("../migrations/0001_create_contact.up.sql"),
+        sqlite_down: include_str!("../migrations/0001_create_contact.down.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "contact_identity_version",
+        up: include_str!("../migrations/0002_contact_identity_version.up.sql"),
+        down: include_str!("../migrations/0002_contact_identity_version.down.sql"),
+        sqlite_up: include_str!("../migrations/0002_contact_identity_version.sqlite.up.sql"),
+        sqlite_down: include_str!("../migrations/0002_contact_identity_version.sqlite.down.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "contact_search",
+        up: include_str!("../migrations/0003_contact_search.up.sql"),
+        down: include_str!("../migrations/0003_contact_search.down.sql"),
+        sqlite_up: include_str!("../migrations/0003_contact_search.sqlite.up.sql"),
+        sqlite_down: include_str!("../migrations/0003_contact_search.sqlite.down.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "contact_soft_delete",
+        up: include_str!("../migrations/0004_contact_soft_delete.up.sql"),
+        down: include_str!("../migrations/0004_contact_soft_delete.down.sql"),
+        sqlite_up: include_str!("../migrations/0004_contact_soft_delete.sqlite.up.sql"),
+        sqlite_down: include_str!("../migrations/0004_contact_soft_delete.sqlite.down.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "contact_autoincrement",
+        up: include_str!("../migrations/0005_contact_autoincrement.up.sql"),
+        down: include_str!("../migrations/0005_contact_autoincrement.down.sql"),
+        sqlite_up: include_str!("../migrations/0005_contact_autoincrement.sqlite.up.sql"),
+        sqlite_down: include_str!("../migrations/0005_contact_autoincrement.sqlite.down.sql"),
+    },
+];
+
+/// Arbitrary key for the advisory lock serializing migrations across replicas.
+const LOCK_KEY: i64 = 0x636f_6e74_6163_7473;
+
+const CREATE_TRACKING_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_migrations (
+    version bigint PRIMARY KEY,
+    name text NOT NULL,
+    applied_at timestamptz NOT NULL DEFAULT now()
+)";
+
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: &'static str,
+    pub applied_at: Option<SystemTime>,
+}
+
+impl MigrationStatus {
+    pub fn describe(&self) -> String {
+        match self.applied_at {
+            Some(at) => format!("{:04} {} applied at {}", self.version, self.name, rfc3339(at)),
+            None => format!("{:04} {} pending", self.version, self.name),
+        }
+    }
+}
+
+/// Applies every pending migration, each in its own transaction, and returns
+/// the versions that were applied.
+pub async fn up(client: &mut Client) -> Result<Vec<i64>, Error> {
+    lock(client).await?;
+    let result = apply_pending(client).await;
+    unlock(client).await?;
+    result
+}
+
+/// Reverts the latest applied migration, returning its version, if any.
+pub async fn down(client: &mut Client) -> Result<Option<i64>, Error> {
+    lock(client).await?;
+    let result = revert_latest(client).await;
+    unlock(client).await?;
+    result
+}
+
+async fn apply_pending(client: &mut Client) -> Result<Vec<i64>, Error> {
+    client.batch_execute(CREATE_TRACKING_TABLE).await?;
+    let applied = applied_versions(client).await?;
+    let mut done = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| !applied.contains(&m.version)) {
+        let tx = client.transaction().await?;
+        tx.batch_execute(migration.up).await?;
+        tx.execute("INSERT INTO schema_migrations (version, name) VALUES ($1, $2)", &[&migration.version, &migration.name]).await?;
+        tx.commit().await?;
+        log!(Level::Info, "migration applied", version = migration.version, name = migration.name);
+        done.push(migration.version);
+    }
+    Ok(done)
+}
+
+async fn revert_latest(client: &mut Client) -> Result<Option<i64>, Error> {
+    let latest = match applied_versions(client).await?.into_iter().max() {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+    let migration = MIGRATIONS
+        .iter()
+        .find(|m| m.version == latest)
+        .ok_or_else(|| Error::Intern(format!("applied migration {} is unknown to this binary", latest)))?;
+    let tx = client.transaction().await?;
+    tx.batch_execute(migration.down).await?;
+    tx.execute("DELETE FROM schema_migrations WHERE version = $1", &[&migration.version]).await?;
+    tx.commit().await?;
+    log!(Level::Info, "migration reverted", version = migration.version, name = migration.name);
+    Ok(Some(latest))
+}
+
+/// Every known migration and when it was applied, without writing anything:
+/// a database that never ran migrations has them all pending.
+pub async fn status(client: &Client) -> Result<Vec<MigrationStatus>, Error> {
+    let rows = if tracking_table_exists(client).await? {
+        client.query("SELECT version, applied_at FROM schema_migrations", &[]).await?
+    } else {
+        Vec::new()
+    };
+    Ok(MIGRATIONS
+        .iter()
+        .map(|m| MigrationStatus {
+            version: m.version,
+            name: m.name,
+            applied_at: rows.iter().find(|r| r.get::<_, i64>(0) == m.version).map(|r| r.get(1)),
+        })
+        .collect())
+}
+
+/// Latest applied version, or `None` when migrations never ran.
+pub async fn current_version(client: &Client) -> Result<Option<i64>, Error> {
+    if !tracking_table_exists(client).await? {
+        return Ok(None);
+    }
+    Ok(client.query_one("SELECT max(version) FROM schema_migrations", &[]).await?.get(0))
+}
+
+async fn tracking_table_exists(client: &Client) -> Result<bool, Error> {
+    Ok(client.query_one("SELECT to_regclass('schema_migrations') IS NOT NULL", &[]).await?.get(0))
+}
+
+async fn applied_versions(client: &Client) -> Result<Vec<i64>, Error> {
+    if !tracking_table_exists(client).await? {
+        return Ok(Vec::new());
+    }
+    let rows = client.query("SELECT version FROM schema_migrations", &[]).await?;
+    Ok(rows.iter().map(|r| r.get(0)).collect())
+}
+
+/// Takes the session advisory lock serializing migrations, so that replicas
+/// starting together apply them one at a time.
+async fn lock(client: &Client) -> Result<(), Error> {
+    client.execute("SELECT pg_advisory_lock($1)", &[&LOCK_KEY]).await?;
+    Ok(())
+}
+
+async fn unlock(client: &Client) -> Result<(), Error> {
+    client.execute("SELECT pg_advisory_unlock($1)", &[&LOCK_KEY]).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::migration::{current_version, down, status, up, MIGRATIONS};
+    use crate::repository::test_dsn;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio_postgres::{Client, NoTls};
+
+    /// Connects with a private, uniquely named schema first in the search
+    /// path, so that migrations can be applied and reverted without touching
+    /// other tests or runs. Returns the schema name for cleanup.
+    async fn isolated_client() -> (Client, String) {
+        static SCHEMAS: AtomicUsize = AtomicUsize::new(0);
+        let schema = format!("migration_test_{}_{}", std::process::id(), SCHEMAS.fetch_add(1, Ordering::Relaxed));
+        let (client, connection) = tokio_postgres::connect(&test_dsn(), NoTls).await.unwrap();
+        tokio::spawn(connection);
+        client
+            .batch_execute(&format!("DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0}; SET search_path TO {0}", schema))
+            .await
+            .unwrap();
+        (client, schema)
+    }
+
+    #[tokio::test]
+    async fn up_down_status() {
+        let (mut client, schema) = isolated_client().await;
+        let latest = MIGRATIONS.last().unwrap().version;
+
+        assert_eq!(current_version(&client).await.unwrap(), None);
+        assert!(status(&client).await.unwrap().iter().all(|s| s.applied_at.is_none()));
+        assert!(down(&mut client).await.unwrap().is_none());
+        let tables: i64 = client
+            .query_one("SELECT count(*) FROM information_schema.tables WHERE table_schema = $1", &[&schema])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(tables, 0, "status and down should not create the tracking table");
+
+        assert_eq!(up(&mut client).await.unwrap().len(), MIGRATIONS.len());
+        assert_eq!(current_version(&client).await.unwrap(), Some(latest));
+        assert!(status(&client).await.unwrap().iter().all(|s| s.applied_at.is_some()));
+        client.execute("INSERT INTO contact (id, lastname) VALUES (1, 'migrated')", &[]).await.unwrap();
+
+        assert!(up(&mut client).await.unwrap().is_empty(), "up should be idempotent");
+
+        assert_eq!(down(&mut client).await.unwrap(), Some(latest));
+        assert_eq!(status(&client).await.unwrap().last().unwrap().applied_at, None);
+
+        client.batch_execute(&format!("DROP SCHEMA {} CASCADE", schema)).await.unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use crate::logging::{log, Level};
+use crate::migration::{MigrationStatus, MIGRATIONS};
+use crate::repository::Error;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use std::time::{Duration, UNIX_EPOCH};
+
+const CREATE_TRACKING_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_migrations (
+    version integer PRIMARY KEY,
+    name text NOT NULL,
+    applied_at integer NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS integer))
+)";
+
+/// Applies every pending migration, each in its own immediate transaction.
+/// Whether a migration is pending is checked once that transaction holds the
+/// write lock, so that a concurrent process waits instead of migrating twice.
+pub fn up(conn: &mut Connection) -> Result<Vec<i64>, Error> {
+    conn.execute_batch(CREATE_TRACKING_TABLE)?;
+    let mut done = Vec::new();
+    for migration in MIGRATIONS {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let applied = tx
+            .query_row("SELECT 1 FROM schema_migrations WHERE version = ?1", params![migration.version], |_| Ok(()))
+            .optional()?
+            .is_some();
+        if applied {
+            continue;
+        }
+        tx.execute_batch(migration.sqlite_up)?;
+        tx.execute("INSERT INTO schema_migrations (version, name) VALUES (?1, ?2)", params![migration.version, migration.name])?;
+        tx.commit()?;
+        log!(Level::Info, "migration applied", version = migration.version, name = migration.name);
+        done.push(migration.version);
+    }
+    Ok(done)
+}
+
+/// Reverts the latest applied migration, returning its version, if any.
+pub fn down(conn: &mut Connection) -> Result<Option<i64>, Error> {
+    let latest = match applied_versions(conn)?.into_iter().max() {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+    let migration = MIGRATIONS
+        .iter()
+        .find(|m| m.version == latest)
+        .ok_or_else(|| Error::Intern(format!("applied migration {} is unknown to this binary", latest)))?;
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    tx.execute_batch(migration.sqlite_down)?;
+    tx.execute("DELETE FROM schema_migrations WHERE version = ?1", params![migration.version])?;
+    tx.commit()?;
+    log!(Level::Info, "migration reverted", version = migration.version, name = migration.name);
+    Ok(Some(latest))
+}
+
+/// Every known migration and when it was applied, without writing anything.
+pub fn status(conn: &Connection) -> Result<Vec<MigrationStatus>, Error> {
+    if !tracking_table_exists(conn)? {
+        return Ok(MIGRATIONS.iter().map(|m| MigrationStatus { version: m.version, name: m.name, applied_at: None }).collect());
+    }
+    let mut statement = conn.prepare("SELECT applied_at FROM schema_migrations WHERE version = ?1")?;
+    MIGRATIONS
+        .iter()
+        .map(|m| {
+            let applied_at: Option<i64> = statement.query_row(params![m.version], |row| row.get(0)).optional()?;
+            Ok(MigrationStatus {
+                version: m.version,
+                name: m.name,
+                applied_at: applied_at.map(|secs| UNIX_EPOCH + Duration::from_secs(secs as u64)),
+            })
+        })
+        .collect()
+}
+
+/// Latest applied version, or `None` when migrations never ran.
+pub fn current_version(conn: &Connection) -> Result<Option<i64>, Error> {
+    if !tracking_table_exists(conn)? {
+        return Ok(None);
+    }
+    Ok(conn.query_row("SELECT max(version) FROM schema_migrations", [], |row| row.get(0))?)
+}
+
+fn tracking_table_exists(conn: &Connection) -> Result<bool, Error> {
+    Ok(conn.query_row(
+        "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'schema_migrations'",
+        [],
+        |row| row.get(0),
+    )?)
+}
+
+fn applied_versions(conn: &Connection) -> Result<Vec<i64>, Error> {
+    if !tracking_table_exists(conn)? {
+        return Ok(Vec::new());
+    }
+    let mut statement = conn.prepare("SELECT version FROM schema_migrations")?;
+    let versions = statement.query_map([], |row| row.get(0))?.collect::<Result<Vec<i64>, _>>()?;
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::migration::sqlite::{current_version, down, status, up};
+    use crate::migration::MIGRATIONS;
+    use rusqlite::Connection;
+
+    #[test]
+    fn up_down_status() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let latest = MIGRATIONS.last().unwrap().version;
+
+        assert_eq!(current_version(&conn).unwrap(), None);
+        assert!(status(&conn).unwrap().iter().all(|s| s.applied_at.is_none()));
+        assert!(down(&mut conn).unwrap().is_none());
+        let tables: i64 = conn.query_row("SELECT count(*) FROM sqlite_master WHERE type = 'table'", [], |row| row.get(0)).unwrap();
+        assert_eq!(tables, 0, "status and down should not create the tracking table");
+
+        assert_eq!(up(&mut conn).unwrap().len(), MIGRATIONS.len());
+        assert_eq!(current_version(&conn).unwrap(), Some(latest));
+        assert!(status(&conn).unwrap().iter().all(|s| s.applied_at.is_some()));
+        conn.execute("INSERT INTO contact (id, lastname) VALUES (1, 'migrated')", []).unwrap();
+
+        assert!(up(&mut conn).unwrap().is_empty(), "up should be idempotent");
+
+        assert_eq!(down(&mut conn).unwrap(), Some(latest));
+        assert_eq!(status(&conn).unwrap().last().unwrap().applied_at, None);
+    }
+
+    #[test]
+    fn concurrent_up_applies_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("migrate.db");
+        let migrate = |path: std::path::PathBuf| {
+            std::thread::spawn(move || {
+                let mut conn = Connection::open(path).unwrap();
+                conn.busy_timeout(std::time::Duration::from_secs(5)).unwrap();
+                up(&mut conn).unwrap()
+            })
+        };
+        let (a, b) = (migrate(path.clone()), migrate(path));
+        let mut applied = a.join().unwrap();
+        applied.extend(b.join().unwrap());
+        applied.sort();
+        assert_eq!(applied, MIGRATIONS.iter().map(|m| m.version).collect::<Vec<_>>(), "each migration should be applied once");
+    }
+}
@@ -0,0 +1,210 @@
+//! Content negotiation: picks the response format from the `Accept` header.
+
+use crate::error::ApiError;
+use crate::extract::FromContext;
+use crate::Context;
+use crate::Response;
+use async_trait::async_trait;
+use hyper::{header, StatusCode};
+use serde::Serialize;
+
+/// A representation handlers can render, in order of preference when the
+/// client accepts several equally.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    MessagePack,
+}
+
+const FORMATS: [Format; 3] = [Format::Json, Format::Csv, Format::MessagePack];
+
+impl Format {
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv",
+            Format::MessagePack => "application/msgpack",
+        }
+    }
+
+    fn matches(self, media_type: &str) -> bool {
+        media_type == self.media_type() || (self == Format::MessagePack && media_type == "application/x-msgpack")
+    }
+
+    /// Picks the format with the highest quality in `accept`, JSON when the
+    /// header is missing, or `None` when the client accepts none of them.
+    pub fn negotiate(accept: Option<&str>) -> Option<Format> {
+        let accept = match accept {
+            Some(accept) if !accept.trim().is_empty() => accept,
+            _ => return Some(Format::Json),
+        };
+        let ranges: Vec<MediaRange> = accept.split(',').filter_map(MediaRange::parse).collect();
+        let mut best: Option<(Format, f32)> = None;
+        for format in FORMATS.iter().copied() {
+            // the most specific range matching the format decides its quality
+            let quality = ranges
+                .iter()
+                .filter(|range| range.matches(format))
+                .max_by_key(|range| range.specificity(format))
+                .map(|range| range.quality)
+                .unwrap_or(0.0);
+            if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+                best = Some((format, quality));
+            }
+        }
+        best.map(|(format, _)| format)
+    }
+
+    /// Renders `value` with `status`. CSV has a header line and one record.
+    pub fn render<T: Serialize>(self, status: StatusCode, value: &T) -> Result<Response, ApiError> {
+        self.render_rows(status, value, None, std::slice::from_ref(value))
+    }
+
+    /// Renders `envelope` with `status`, except for CSV, which has no room for
+    /// it and lists `rows` as records under `columns`, written even when there
+    /// are no rows. `columns` must follow the field order of `R`.
+    pub fn render_list<T: Serialize, R: Serialize>(
+        self,
+        status: StatusCode,
+        envelope: &T,
+        columns: &[&str],
+        rows: &[R],
+    ) -> Result<Response, ApiError> {
+        self.render_rows(status, envelope, Some(columns), rows)
+    }
+
+    /// Without `columns`, the CSV header comes from the fields of the first row.
+    fn render_rows<T: Serialize, R: Serialize>(
+        self,
+        status: StatusCode,
+        envelope: &T,
+        columns: Option<&[&str]>,
+        rows: &[R],
+    ) -> Result<Response, ApiError> {
+        let body = match self {
+            Format::Json => serde_json::to_vec(envelope).map_err(|e| ApiError::Internal(e.to_string()))?,
+            Format::MessagePack => rmp_serde::to_vec_named(envelope).map_err(|e| ApiError::Internal(e.to_string()))?,
+            Format::Csv => {
+                let mut writer = csv::WriterBuilder::new().has_headers(columns.is_none()).from_writer(Vec::new());
+                if let Some(columns) = columns {
+                    writer.write_record(columns).map_err(|e| ApiError::Internal(e.to_string()))?;
+                }
+                for row in rows {
+                    writer.serialize(row).map_err(|e| ApiError::Internal(e.to_string()))?;
+                }
+                writer.into_inner().map_err(|e| ApiError::Internal(e.to_string()))?
+            }
+        };
+        Ok(hyper::Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, self.media_type())
+            .header(header::VARY, "accept")
+            .body(body.into())
+            .unwrap())
+    }
+}
+
+/// The format negotiated from the `Accept` header, failing with 406.
+#[async_trait]
+impl FromContext for Format {
+    async fn from_context(ctx: &mut Context) -> Result<Self, ApiError> {
+        let accept = ctx.req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok());
+        Format::negotiate(accept).ok_or(ApiError::NotAcceptable)
+    }
+}
+
+/// One entry of an `Accept` header, such as `text/*;q=0.5`.
+struct MediaRange<'a> {
+    kind: &'a str,
+    subtype: &'a str,
+    quality: f32,
+}
+
+impl<'a> MediaRange<'a> {
+    fn parse(range: &'a str) -> Option<MediaRange<'a>> {
+        let mut parts = range.split(';');
+        let (kind, subtype) = parts.next()?.trim().split_once('/')?;
+        let mut quality = 1.0;
+        for param in parts {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    quality = value.trim().parse().ok()?;
+                }
+            }
+        }
+        Some(MediaRange { kind: kind.trim(), subtype: subtype.trim(), quality })
+    }
+
+    fn matches(&self, format: Format) -> bool {
+        match (self.kind, self.subtype) {
+            ("*", "*") => true,
+            (kind, "*") => format.media_type().split('/').next().is_some_and(|k| k.eq_ignore_ascii_case(kind)),
+            (kind, subtype) => format.matches(&format!("{}/{}", kind, subtype).to_ascii_lowercase()),
+        }
+    }
+
+    fn specificity(&self, format: Format) -> u8 {
+        match (self.kind, self.subtype) {
+            ("*", "*") => 0,
+            (_, "*") => 1,
+            _ if self.matches(format) => 2,
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::negotiate::Format;
+    use crate::testing::TestApp;
+    use hyper::{Body, Request, StatusCode};
+
+    #[test]
+    fn negotiates_accept_header() {
+        assert_eq!(Format::negotiate(None), Some(Format::Json));
+        assert_eq!(Format::negotiate(Some("*/*")), Some(Format::Json));
+        assert_eq!(Format::negotiate(Some("text/csv")), Some(Format::Csv));
+        assert_eq!(Format::negotiate(Some("application/json;q=0.5, application/msgpack")), Some(Format::MessagePack));
+        assert_eq!(Format::negotiate(Some("text/*, application/json;q=0.9")), Some(Format::Csv));
+        assert_eq!(Format::negotiate(Some("*/*, application/json;q=0")), Some(Format::Csv));
+        assert_eq!(Format::negotiate(Some("application/x-msgpack")), Some(Format::MessagePack));
+        assert_eq!(Format::negotiate(Some("text/html")), None);
+    }
+
+    async fn get(app: &TestApp, uri: &str, accept: &str) -> crate::testing::TestResponse {
+        app.request(Request::get(uri).header("accept", accept).body(Body::empty()).unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn renders_negotiated_format() {
+        let app = TestApp::new();
+        let contact = r#"{"firstname":"Ada","lastname":"Lovelace","phone":"","email":"ada@example.com"}"#;
+        app.send(hyper::Method::POST, "/contacts", Some(contact)).await;
+
+        let resp = get(&app, "/contacts/1", "application/json").await;
+        assert_eq!(resp.header("content-type"), Some("application/json"));
+        assert_eq!(resp.json()["lastname"], "Lovelace");
+
+        let resp = get(&app, "/contacts", "text/csv").await;
+        assert_eq!(resp.header("content-type"), Some("text/csv"));
+        assert_eq!(resp.text(), "id,firstname,lastname,phone,email,version\n1,Ada,Lovelace,,ada@example.com,1\n");
+
+        let resp = get(&app, "/contacts/1", "application/msgpack").await;
+        assert_eq!(resp.header("content-type"), Some("application/msgpack"));
+        let decoded: serde_json::Value = rmp_serde::from_slice(&resp.body).unwrap();
+        assert_eq!(decoded["email"], "ada@example.com");
+
+        let resp = get(&app, "/contacts/1", "text/html").await;
+        assert_eq!(resp.status, StatusCode::NOT_ACCEPTABLE);
+        assert_eq!(resp.header("content-type"), Some("application/problem+json"));
+    }
+
+    #[tokio::test]
+    async fn renders_csv_header_for_empty_list() {
+        let app = TestApp::new();
+        let resp = get(&app, "/contacts", "text/csv").await;
+        assert_eq!(resp.status, StatusCode::OK);
+        assert_eq!(resp.text(), "id,firstname,lastname,phone,email,version\n");
+    }
+}
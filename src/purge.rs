@@ -0,0 +1,56 @@
+//! Background purge of the trash: contacts deleted longer ago than the
+//! retention period are deleted for good.
+
+use crate::logging::{log, Level};
+use crate::repository::{Error, Repository};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+
+/// How often the trash is checked for contacts past their retention.
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Purges the trash right away, then every `PURGE_INTERVAL`, until the
+/// returned task is aborted. A failed purge is logged and retried at the
+/// next tick.
+pub fn spawn(repository: Arc<dyn Repository + Send + Sync>, retention: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = purge(repository.as_ref(), retention).await {
+                log!(Level::Warn, "trash purge failed", error = e.to_string());
+            }
+        }
+    })
+}
+
+/// Deletes for good the contacts deleted more than `retention` ago.
+pub async fn purge(repository: &dyn Repository, retention: Duration) -> Result<u64, Error> {
+    let before = SystemTime::now().checked_sub(retention).unwrap_or(SystemTime::UNIX_EPOCH);
+    let purged = repository.purge(before).await?;
+    if purged > 0 {
+        log!(Level::Info, "trash purged", contacts = purged);
+    }
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::purge::purge;
+    use crate::repository::{InMemoryRepository, Repository};
+    use crate::testing::contact;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn purges_past_retention() {
+        let repository = InMemoryRepository::default();
+        let id = repository.save(&contact("doe", "", "")).await.unwrap().id;
+        repository.delete(id).await.unwrap();
+
+        assert_eq!(purge(&repository, Duration::from_secs(86_400)).await.unwrap(), 0, "recent deletes should be kept");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(purge(&repository, Duration::from_millis(1)).await.unwrap(), 1);
+        assert_eq!(repository.trash(10, None).await.unwrap().total, 0)
+    }
+}
@@ -0,0 +1,138 @@
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+pub const DEFAULT_LIMIT: i64 = 20;
+pub const MAX_LIMIT: i64 = 100;
+
+/// Listing criteria for contacts, deserialized from the `GET /contacts` query string.
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct ContactQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+    pub after: Option<i32>,
+    pub lastname: Option<String>,
+    pub email_domain: Option<String>,
+    pub phone_prefix: Option<String>,
+    #[serde(deserialize_with = "deserialize_sort")]
+    pub sort: Vec<SortKey>,
+}
+
+impl ContactQuery {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+
+    /// Sort keys with `id` appended as a tie-breaker, so that ordering is total
+    /// and `after` cursors are stable.
+    pub fn sort_keys(&self) -> Vec<SortKey> {
+        let mut keys = self.sort.clone();
+        if !keys.iter().any(|k| k.field == SortField::Id) {
+            keys.push(SortKey { field: SortField::Id, descending: false });
+        }
+        keys
+    }
+}
+
+/// Paging of `GET /contacts/trash`, by id.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct TrashQuery {
+    pub limit: Option<i64>,
+    pub after: Option<i32>,
+}
+
+impl TrashQuery {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SortField {
+    Id,
+    Firstname,
+    Lastname,
+    Phone,
+    Email,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SortKey {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (descending, name) = match s.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, s),
+        };
+        let field = match name {
+            "id" => SortField::Id,
+            "firstname" => SortField::Firstname,
+            "lastname" => SortField::Lastname,
+            "phone" => SortField::Phone,
+            "email" => SortField::Email,
+            _ => return Err(format!("unknown sort field '{}'", name)),
+        };
+        Ok(SortKey { field, descending })
+    }
+}
+
+fn deserialize_sort<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<SortKey>, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+    raw.split(',')
+        .filter(|key| !key.is_empty())
+        .map(|key| key.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+#[derive(Serialize, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub next_cursor: Option<i32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::{ContactQuery, SortField, SortKey, MAX_LIMIT};
+
+    #[test]
+    fn parse_query_string() {
+        let query: ContactQuery = serde_urlencoded::from_str("limit=5&after=12&email_domain=mail.com&sort=lastname,-id").unwrap();
+        assert_eq!(query.limit(), 5);
+        assert_eq!(query.after, Some(12));
+        assert_eq!(query.email_domain.as_deref(), Some("mail.com"));
+        assert_eq!(query.sort, vec![
+            SortKey { field: SortField::Lastname, descending: false },
+            SortKey { field: SortField::Id, descending: true },
+        ]);
+    }
+
+    #[test]
+    fn limit_is_clamped() {
+        let query: ContactQuery = serde_urlencoded::from_str("limit=5000").unwrap();
+        assert_eq!(query.limit(), MAX_LIMIT)
+    }
+
+    #[test]
+    fn unknown_sort_field_is_rejected() {
+        assert!(serde_urlencoded::from_str::<ContactQuery>("sort=password").is_err())
+    }
+
+    #[test]
+    fn id_is_appended_as_tie_breaker() {
+        let query: ContactQuery = serde_urlencoded::from_str("sort=-lastname").unwrap();
+        assert_eq!(query.sort_keys().last(), Some(&SortKey { field: SortField::Id, descending: false }))
+    }
+}
@@ -1,139 +1,1041 @@
-use tokio_postgres::{Client, NoTls, Error as PgError};
+use crate::logging::{log, rfc3339, Level};
+use crate::metrics::metrics;
+use crate::migration::{self, MigrationStatus};
+use crate::query::{ContactQuery, Page, MAX_LIMIT};
+use crate::search::{self, SearchHit};
+use crate::validation::{Validate, ValidationErrors, Validator};
+use tokio_postgres::{Client, Config as PgConfig, NoTls, Row, Error as PgError, types::ToSql};
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize};
+use bb8::{ManageConnection, Pool, PooledConnection, RunError};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, Mutex};
 
-#[derive(Serialize, Deserialize)]
+mod memory;
+mod sql;
+mod sqlite;
+
+pub use memory::InMemoryRepository;
+use sql::{Dialect, ListStatements};
+pub use sqlite::SqliteRepository;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Contact {
+    /// Generated by the store on `save`. Ignored in the body of a create or
+    /// update, but bulk upserts match rows on it.
+    #[serde(default)]
     pub id: i32,
     pub firstname: String,
     pub lastname: String,
     pub phone: String,
-    pub email: String
+    pub email: String,
+    /// Incremented by every update, starting at 1, and sent as the `ETag`.
+    #[serde(default)]
+    pub version: i32,
+}
+
+/// A contact in the trash, until it is restored or purged.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct DeletedContact {
+    #[serde(flatten)]
+    pub contact: Contact,
+    #[serde(serialize_with = "serialize_time")]
+    pub deleted_at: SystemTime,
+}
+
+fn serialize_time<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&rfc3339(*time))
+}
+
+#[derive(Deserialize, Default)]
+pub struct ContactPatch {
+    pub firstname: Option<String>,
+    pub lastname: Option<String>,
+    pub phone: Option<String>,
+    pub email: Option<String>
+}
+
+const MAX_NAME_LENGTH: usize = 255;
+const MAX_EMAIL_LENGTH: usize = 255;
+
+impl Validate for Contact {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        Validator::new()
+            .max_length("firstname", &self.firstname, MAX_NAME_LENGTH)
+            .required("lastname", &self.lastname)
+            .max_length("lastname", &self.lastname, MAX_NAME_LENGTH)
+            .phone("phone", &self.phone)
+            .email("email", &self.email)
+            .max_length("email", &self.email, MAX_EMAIL_LENGTH)
+            .finish()
+    }
+}
+
+impl Validate for ContactPatch {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut validator = Validator::new();
+        if let Some(firstname) = &self.firstname {
+            validator.max_length("firstname", firstname, MAX_NAME_LENGTH);
+        }
+        if let Some(lastname) = &self.lastname {
+            validator.required("lastname", lastname).max_length("lastname", lastname, MAX_NAME_LENGTH);
+        }
+        if let Some(phone) = &self.phone {
+            validator.phone("phone", phone);
+        }
+        if let Some(email) = &self.email {
+            validator.email("email", email).max_length("email", email, MAX_EMAIL_LENGTH);
+        }
+        validator.finish()
+    }
+}
+
+impl Contact {
+    pub fn apply(&mut self, patch: ContactPatch) {
+        if let Some(firstname) = patch.firstname { self.firstname = firstname }
+        if let Some(lastname) = patch.lastname { self.lastname = lastname }
+        if let Some(phone) = patch.phone { self.phone = phone }
+        if let Some(email) = patch.email { self.email = email }
+    }
 }
 
 #[async_trait]
-pub trait Repository {
-    async fn new(dsl: &str) -> Self;
+pub trait Repository: Send + Sync {
+    /// Every method but `exists`, `trash`, `restore`, `purge` and `upsert`
+    /// ignores the contacts in the trash, as if they did not exist.
     async fn get(&self, id: i32) -> Result<Contact, Error>;
-    async fn save(&self, contact: &Contact) -> Result<u64, Error>;
+    /// Whether a contact has this id, in the trash or not, that is whether
+    /// `upsert` would update rather than create it.
+    async fn exists(&self, id: i32) -> Result<bool, Error>;
+    async fn list(&self, query: &ContactQuery) -> Result<Page<Contact>, Error>;
+    /// Inserts a new contact under a generated id, ignoring `contact.id` and
+    /// `contact.version`, and returns it as stored.
+    async fn save(&self, contact: &Contact) -> Result<Contact, Error>;
+    /// Replaces the contact with `contact.id` and bumps its version. With
+    /// `expected_version`, fails with `Error::VersionMismatch` when the
+    /// stored contact has changed since that version was read.
+    async fn update(&self, contact: &Contact, expected_version: Option<i32>) -> Result<Contact, Error>;
+    /// Moves the contact to the trash, returning 0 when there is no such
+    /// contact outside of it.
+    async fn delete(&self, id: i32) -> Result<u64, Error>;
+    /// Contacts in the trash, by id, starting after the `after` id.
+    async fn trash(&self, limit: i64, after: Option<i32>) -> Result<Page<DeletedContact>, Error>;
+    /// Takes the contact out of the trash and bumps its version.
+    async fn restore(&self, id: i32) -> Result<Contact, Error>;
+    /// Deletes for good the contacts moved to the trash before `before`,
+    /// returning how many.
+    async fn purge(&self, before: SystemTime) -> Result<u64, Error>;
+    /// Inserts each contact, or replaces the one with the same id, taking it
+    /// out of the trash, and bumps its version, all in one transaction.
+    /// Contacts without an id (0) get a generated one. A failing row does not
+    /// abort the others, it gets its own error in the returned outcomes,
+    /// listed in input order.
+    async fn upsert(&self, contacts: &[Contact]) -> Result<Vec<Result<Upserted, Error>>, Error>;
+
+    /// Contacts matching every word of `q`, as a prefix or misspelled, best
+    /// first. This scans every contact, stores with full-text search should
+    /// override it.
+    async fn search(&self, q: &str, limit: i64) -> Result<Vec<SearchHit>, Error> {
+        let terms = search::terms(q);
+        let mut hits = Vec::new();
+        let mut query = ContactQuery { limit: Some(MAX_LIMIT), ..ContactQuery::default() };
+        loop {
+            let page = self.list(&query).await?;
+            hits.extend(page.items.into_iter().filter_map(|contact| {
+                search::score(&contact, &terms).map(|score| SearchHit { contact, score })
+            }));
+            match page.next_cursor {
+                Some(after) => query.after = Some(after),
+                None => break,
+            }
+        }
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.contact.id.cmp(&b.contact.id)));
+        hits.truncate(limit.max(0) as usize);
+        Ok(hits)
+    }
+
+    /// Checks that the backing store answers.
+    async fn ping(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Latest applied schema migration, `None` for stores without a schema.
+    async fn schema_version(&self) -> Result<Option<i64>, Error> {
+        Ok(None)
+    }
+
+    async fn migrate_up(&self) -> Result<Vec<i64>, Error> {
+        Ok(Vec::new())
+    }
+
+    async fn migrate_down(&self) -> Result<Option<i64>, Error> {
+        Ok(None)
+    }
+
+    async fn migration_status(&self) -> Result<Vec<MigrationStatus>, Error> {
+        Ok(Vec::new())
+    }
+
+    /// Connection pool usage, for stores that pool connections.
+    fn pool_status(&self) -> Option<PoolStatus> {
+        None
+    }
+
+    /// Releases the resources held by the store once the server stopped.
+    async fn close(&self) {}
+}
+
+/// What `Repository::upsert` did with a contact, which is returned as stored.
+#[derive(Debug, PartialEq)]
+pub enum Upserted {
+    Created(Contact),
+    Updated(Contact),
+}
+
+/// Storage backend, chosen by the scheme of the database url.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Postgres,
+    Sqlite,
+    Memory,
+}
+
+impl Backend {
+    pub fn from_url(url: &str) -> Backend {
+        if url.starts_with("memory:") {
+            Backend::Memory
+        } else if url.starts_with("sqlite:") {
+            Backend::Sqlite
+        } else {
+            Backend::Postgres
+        }
+    }
 }
 
+/// Opens the repository the database url points to.
+pub async fn connect(url: &str, pool: &PoolConfig) -> Result<Arc<dyn Repository + Send + Sync>, Error> {
+    Ok(match Backend::from_url(url) {
+        Backend::Postgres => Arc::new(PgsqlRepository::with_config(url, pool).await?),
+        Backend::Sqlite => Arc::new(SqliteRepository::open(url)?),
+        Backend::Memory => Arc::new(InMemoryRepository::default()),
+    })
+}
+
+pub struct PoolConfig {
+    pub min_size: Option<u32>,
+    pub max_size: u32,
+    pub acquire_timeout: Duration,
+    pub test_on_checkout: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            min_size: None,
+            max_size: 10,
+            acquire_timeout: Duration::from_secs(30),
+            test_on_checkout: true,
+        }
+    }
+}
+
+/// Opens pooled Postgres connections, keeping track of the background tasks
+/// driving them so that `PgsqlRepository::close` can wait for their termination.
+struct PgConnectionManager {
+    config: PgConfig,
+    tasks: mpsc::Sender<()>,
+}
+
+#[async_trait]
+impl ManageConnection for PgConnectionManager {
+    type Connection = Client;
+    type Error = PgError;
+
+    async fn connect(&self) -> Result<Client, PgError> {
+        let (client, connection) = self.config.connect(NoTls).await?;
+        let task = self.tasks.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log!(Level::Error, "connection error", error = e.to_string());
+            }
+            drop(task);
+        });
+        Ok(client)
+    }
+
+    async fn is_valid(&self, conn: &mut Client) -> Result<(), PgError> {
+        conn.simple_query("").await.map(|_| ())
+    }
+
+    fn has_broken(&self, conn: &mut Client) -> bool {
+        conn.is_closed()
+    }
+}
+
+type PgPool = Pool<PgConnectionManager>;
+
 pub struct PgsqlRepository {
-    client: Client
+    /// Taken out by `close`, after which every operation fails as unavailable.
+    pool: RwLock<Option<PgPool>>,
+    max_size: u32,
+    tasks: Mutex<mpsc::Receiver<()>>,
+}
+
+pub struct PoolStatus {
+    pub connections: u32,
+    pub idle: u32,
+    pub max_size: u32,
 }
 
 #[derive(Debug)]
 pub enum Error {
     Db(PgError),
+    Sqlite(rusqlite::Error),
+    NotFound,
+    VersionMismatch,
+    Unavailable,
     Intern(String),
 }
 
+impl Error {
+    /// Short name of the variant, used as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Db(_) | Error::Sqlite(_) => "db",
+            Error::NotFound => "not_found",
+            Error::VersionMismatch => "version_mismatch",
+            Error::Unavailable => "unavailable",
+            Error::Intern(_) => "intern",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Db(err) => write!(f, "database error: {}", err),
+            Error::Sqlite(err) => write!(f, "database error: {}", err),
+            Error::NotFound => write!(f, "not found"),
+            Error::VersionMismatch => write!(f, "version mismatch"),
+            Error::Unavailable => write!(f, "timed out waiting for a database connection"),
+            Error::Intern(err) => write!(f, "internal error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl From<PgError> for Error {
     fn from(err: PgError) -> Error {
         Error::Db(err)
     }
 }
 
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error {
+        Error::Sqlite(err)
+    }
+}
+
+impl From<RunError<PgError>> for Error {
+    fn from(err: RunError<PgError>) -> Error {
+        match err {
+            RunError::User(err) => Error::Db(err),
+            RunError::TimedOut => Error::Unavailable,
+        }
+    }
+}
+
 impl From<String> for Error {
     fn from(err: String) -> Error {
         Error::Intern(err)
     }
 }
 
+impl PgsqlRepository {
+    pub async fn with_config(dsn: &str, config: &PoolConfig) -> Result<Self, Error> {
+        let (sender, receiver) = mpsc::channel(1);
+        let manager = PgConnectionManager { config: dsn.parse()?, tasks: sender };
+        let pool = Pool::builder()
+            .min_idle(config.min_size)
+            .max_size(config.max_size)
+            .connection_timeout(config.acquire_timeout)
+            .test_on_check_out(config.test_on_checkout)
+            .build(manager)
+            .await?;
+        Ok(Self { pool: RwLock::new(Some(pool)), max_size: config.max_size, tasks: Mutex::new(receiver) })
+    }
+
+    fn pool(&self) -> Result<PgPool, Error> {
+        self.pool.read().unwrap().clone().ok_or(Error::Unavailable)
+    }
+
+    async fn execute(&self, operation: &str, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, Error> {
+        let conn = self.conn().await?;
+        let start = Instant::now();
+        let result = conn.execute(sql, params).await;
+        log_query(operation, sql, start, result.as_ref().copied());
+        Ok(result?)
+    }
+
+    async fn conn(&self) -> Result<PooledConnection<'static, PgConnectionManager>, Error> {
+        self.pool()?.get_owned().await.map_err(|e| {
+            let err = Error::from(e);
+            metrics().count_db_error(&err);
+            err
+        })
+    }
+
+}
+
 #[async_trait]
 impl Repository for PgsqlRepository {
-    async fn new(dsn: &str) -> Self {
-        let (client, connection) = tokio_postgres::connect(dsn, NoTls).await.unwrap();
+    async fn get(&self, id: i32) -> Result<Contact, Error> {
+        let sql = "SELECT id, firstname, lastname, phone, email, version FROM contact WHERE id=$1 AND deleted_at IS NULL";
+        let conn = self.conn().await?;
+        let start = Instant::now();
+        let result = conn.query_opt(sql, &[&id]).await;
+        log_query("get", sql, start, result.as_ref().map(|row| row.is_some() as u64));
+        result?.map(|row| contact_from_row(&row)).ok_or(Error::NotFound)
+    }
 
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                eprintln!("connection error: {}", e);
+    async fn exists(&self, id: i32) -> Result<bool, Error> {
+        let sql = "SELECT 1 FROM contact WHERE id=$1";
+        let conn = self.conn().await?;
+        let start = Instant::now();
+        let result = conn.query_opt(sql, &[&id]).await;
+        log_query("exists", sql, start, result.as_ref().map(|row| row.is_some() as u64));
+        Ok(result?.is_some())
+    }
+
+    async fn list(&self, query: &ContactQuery) -> Result<Page<Contact>, Error> {
+        let statements = ListStatements::new(query, Dialect::Postgres);
+        let params: Vec<&(dyn ToSql + Sync)> = statements.params.iter().map(|p| p as &(dyn ToSql + Sync)).collect();
+        let conn = self.conn().await?;
+        let start = Instant::now();
+        let result = conn.query_one(statements.count.as_str(), &params[..statements.count_params]).await;
+        log_query("count", &statements.count, start, result.as_ref().map(|_| 1));
+        let total: i64 = result?.get(0);
+
+        let limit = query.limit();
+        let start = Instant::now();
+        let result = conn.query(statements.select.as_str(), &params).await;
+        log_query("list", &statements.select, start, result.as_ref().map(|rows| rows.len() as u64));
+        let rows = result?;
+
+        let mut items: Vec<Contact> = rows.iter().map(contact_from_row).collect();
+        let next_cursor = if items.len() as i64 > limit {
+            items.truncate(limit as usize);
+            items.last().map(|c| c.id)
+        } else {
+            None
+        };
+        Ok(Page { items, total, next_cursor })
+    }
+
+    async fn save(&self, contact: &Contact) -> Result<Contact, Error> {
+        let sql = "INSERT INTO contact (firstname, lastname, phone, email) VALUES ($1, $2, $3, $4) \
+                   RETURNING id, firstname, lastname, phone, email, version";
+        let conn = self.conn().await?;
+        let start = Instant::now();
+        let result = conn.query_one(sql, &[&contact.firstname, &contact.lastname, &contact.phone, &contact.email]).await;
+        log_query("save", sql, start, result.as_ref().map(|_| 1));
+        Ok(contact_from_row(&result?))
+    }
+
+    async fn update(&self, contact: &Contact, expected_version: Option<i32>) -> Result<Contact, Error> {
+        let sql = "UPDATE contact SET firstname=$2, lastname=$3, phone=$4, email=$5, version=version + 1 \
+                   WHERE id=$1 AND deleted_at IS NULL AND ($6::integer IS NULL OR version=$6) \
+                   RETURNING id, firstname, lastname, phone, email, version";
+        let conn = self.conn().await?;
+        let start = Instant::now();
+        let result = conn.query_opt(sql, &[&contact.id, &contact.firstname, &contact.lastname, &contact.phone, &contact.email, &expected_version]).await;
+        log_query("update", sql, start, result.as_ref().map(|row| row.is_some() as u64));
+        match result? {
+            Some(row) => Ok(contact_from_row(&row)),
+            // nothing matched: tell a missing contact from a stale version
+            None => match expected_version {
+                Some(_) if conn.query_opt("SELECT 1 FROM contact WHERE id=$1 AND deleted_at IS NULL", &[&contact.id]).await?.is_some() => Err(Error::VersionMismatch),
+                _ => Err(Error::NotFound),
+            },
+        }
+    }
+
+    async fn delete(&self, id: i32) -> Result<u64, Error> {
+        self.execute("delete", "UPDATE contact SET deleted_at=now() WHERE id=$1 AND deleted_at IS NULL", &[&id]).await
+    }
+
+    async fn trash(&self, limit: i64, after: Option<i32>) -> Result<Page<DeletedContact>, Error> {
+        let count = "SELECT count(*) FROM contact WHERE deleted_at IS NOT NULL";
+        let select = "SELECT id, firstname, lastname, phone, email, version, deleted_at FROM contact \
+                      WHERE deleted_at IS NOT NULL AND ($1::integer IS NULL OR id > $1) ORDER BY id LIMIT $2";
+        let conn = self.conn().await?;
+        let start = Instant::now();
+        let result = conn.query_one(count, &[]).await;
+        log_query("count", count, start, result.as_ref().map(|_| 1));
+        let total: i64 = result?.get(0);
+        let start = Instant::now();
+        let result = conn.query(select, &[&after, &(limit + 1)]).await;
+        log_query("trash", select, start, result.as_ref().map(|rows| rows.len() as u64));
+        let mut items: Vec<DeletedContact> = result?.iter()
+            .map(|row| DeletedContact { contact: contact_from_row(row), deleted_at: row.get(6) })
+            .collect();
+        let next_cursor = if items.len() as i64 > limit {
+            items.truncate(limit as usize);
+            items.last().map(|c| c.contact.id)
+        } else {
+            None
+        };
+        Ok(Page { items, total, next_cursor })
+    }
+
+    async fn restore(&self, id: i32) -> Result<Contact, Error> {
+        let sql = "UPDATE contact SET deleted_at=NULL, version=version + 1 WHERE id=$1 AND deleted_at IS NOT NULL \
+                   RETURNING id, firstname, lastname, phone, email, version";
+        let conn = self.conn().await?;
+        let start = Instant::now();
+        let result = conn.query_opt(sql, &[&id]).await;
+        log_query("restore", sql, start, result.as_ref().map(|row| row.is_some() as u64));
+        result?.map(|row| contact_from_row(&row)).ok_or(Error::NotFound)
+    }
+
+    async fn purge(&self, before: SystemTime) -> Result<u64, Error> {
+        self.execute("purge", "DELETE FROM contact WHERE deleted_at < $1", &[&before]).await
+    }
+
+    async fn upsert(&self, contacts: &[Contact]) -> Result<Vec<Result<Upserted, Error>>, Error> {
+        let insert = "INSERT INTO contact (firstname, lastname, phone, email) VALUES ($1, $2, $3, $4) \
+                      RETURNING id, firstname, lastname, phone, email, version, true";
+        let upsert = "INSERT INTO contact (id, firstname, lastname, phone, email) VALUES ($1, $2, $3, $4, $5) \
+                      ON CONFLICT (id) DO UPDATE SET firstname=EXCLUDED.firstname, lastname=EXCLUDED.lastname, \
+                      phone=EXCLUDED.phone, email=EXCLUDED.email, version=contact.version + 1, deleted_at=NULL \
+                      RETURNING id, firstname, lastname, phone, email, version, xmax = 0";
+        // explicit ids bypass the identity sequence, which must not hand them out later
+        let advance_sequence = "SELECT setval(seq, $1) FROM (SELECT pg_get_serial_sequence('contact', 'id')::regclass AS seq) s \
+                                WHERE $1 > COALESCE(pg_sequence_last_value(seq), 0)";
+        let mut conn = self.conn().await?;
+        let mut tx = conn.transaction().await?;
+        // before the inserts, and setval is not rolled back, so that a concurrent
+        // `save` never draws one of the explicit ids
+        if let Some(max_id) = contacts.iter().map(|c| c.id).max().filter(|id| *id > 0) {
+            tx.execute(advance_sequence, &[&i64::from(max_id)]).await?;
+        }
+        let mut outcomes = Vec::with_capacity(contacts.len());
+        for contact in contacts {
+            // a savepoint per row, so that a failing row leaves the others in the transaction
+            let savepoint = tx.transaction().await?;
+            let start = Instant::now();
+            let (sql, result) = if contact.id == 0 {
+                (insert, savepoint.query_one(insert, &[&contact.firstname, &contact.lastname, &contact.phone, &contact.email]).await)
+            } else {
+                (upsert, savepoint.query_one(upsert, &[&contact.id, &contact.firstname, &contact.lastname, &contact.phone, &contact.email]).await)
+            };
+            log_query("upsert", sql, start, result.as_ref().map(|_| 1));
+            match result {
+                Ok(row) => {
+                    savepoint.commit().await?;
+                    let stored = contact_from_row(&row);
+                    outcomes.push(Ok(if row.get(6) { Upserted::Created(stored) } else { Upserted::Updated(stored) }));
+                }
+                Err(err) => {
+                    savepoint.rollback().await?;
+                    outcomes.push(Err(Error::Db(err)));
+                }
             }
-        });
+        }
+        tx.commit().await?;
+        Ok(outcomes)
+    }
 
-        Self { client }
+    /// Full-text search over the names and email, each word of `q` matching
+    /// as a prefix, or trigram similarity over the whole contact to catch
+    /// misspellings, ranked by both.
+    async fn search(&self, q: &str, limit: i64) -> Result<Vec<SearchHit>, Error> {
+        let sql = "SELECT id, firstname, lastname, phone, email, version, \
+                   COALESCE(ts_rank(search, query), 0) + public.word_similarity($1, document) AS score \
+                   FROM contact CROSS JOIN LATERAL (SELECT to_tsquery('simple', $2) AS query, \
+                   COALESCE(firstname, '') || ' ' || lastname || ' ' || COALESCE(email, '') AS document) s \
+                   WHERE deleted_at IS NULL AND (search @@ query OR $1 OPERATOR(public.<%) document) \
+                   ORDER BY score DESC, id LIMIT $3";
+        let terms = search::terms(q);
+        // every term as a prefix, which leaves no room for tsquery syntax
+        let prefixes = Some(terms.iter().map(|t| format!("{}:*", t)).collect::<Vec<_>>().join(" & ")).filter(|p| !p.is_empty());
+        let mut conn = self.conn().await?;
+        let tx = conn.transaction().await?;
+        // the default threshold of 0.6 misses a single letter left out of a short name
+        tx.execute("SELECT set_config('pg_trgm.word_similarity_threshold', $1, true)", &[&search::FUZZY_THRESHOLD.to_string()]).await?;
+        let start = Instant::now();
+        let result = tx.query(sql, &[&q, &prefixes, &limit]).await;
+        log_query("search", sql, start, result.as_ref().map(|rows| rows.len() as u64));
+        let hits = result?.iter().map(|row| SearchHit { contact: contact_from_row(row), score: row.get(6) }).collect();
+        tx.commit().await?;
+        Ok(hits)
     }
 
-    async fn get(&self, id: i32) -> Result<Contact, Error> {
-        let row = self.client.query_one("SELECT id, firstname, lastname, phone, email FROM contact WHERE id=$1", &[&id]).await?;
-        let row_firstname : Option<String> =  row.get(1);
-        let row_phone : Option<String> =  row.get(3);
-        let row_email : Option<String> =  row.get(4);
-        Ok(Contact {
-            id: row.get(0),
-            firstname: row_firstname.unwrap_or(String::from("")),
-            lastname: row.get(2),
-            phone: row_phone.unwrap_or(String::from("")),
-            email: row_email.unwrap_or(String::from("")),
-        })
-        
+    /// Runs a trivial statement to check that the database answers.
+    async fn ping(&self) -> Result<(), Error> {
+        let conn = self.conn().await?;
+        let start = Instant::now();
+        let result = conn.simple_query("SELECT 1").await;
+        log_query("ping", "SELECT 1", start, result.as_ref().map(|_| 1));
+        result?;
+        Ok(())
+    }
+
+    async fn schema_version(&self) -> Result<Option<i64>, Error> {
+        migration::current_version(&*self.conn().await?).await
+    }
+
+    async fn migrate_up(&self) -> Result<Vec<i64>, Error> {
+        migration::up(&mut *self.conn().await?).await
+    }
+
+    async fn migrate_down(&self) -> Result<Option<i64>, Error> {
+        migration::down(&mut *self.conn().await?).await
     }
 
-    async fn save(&self, contact: &Contact) -> Result<u64, Error> {
-        Ok(self.client.execute("INSERT INTO contact (id, firstname, lastname, phone, email) VALUES ($1, $2, $3, $4, $5)",
-                            &[&contact.id, &contact.firstname, &contact.lastname, &contact.phone, &contact.email]).await?)
+    async fn migration_status(&self) -> Result<Vec<MigrationStatus>, Error> {
+        migration::status(&*self.conn().await?).await
     }
+
+    fn pool_status(&self) -> Option<PoolStatus> {
+        let state = self.pool.read().unwrap().as_ref()?.state();
+        Some(PoolStatus { connections: state.connections, idle: state.idle_connections, max_size: self.max_size })
+    }
+
+    /// Drops every pooled connection and waits until Postgres has been told
+    /// to terminate each session.
+    async fn close(&self) {
+        let pool = self.pool.write().unwrap().take();
+        drop(pool);
+        // the channel closes once the last connection task drops its sender
+        self.tasks.lock().await.recv().await;
+    }
+}
+
+/// Logs a finished statement at debug level, or at warn level when it failed,
+/// and records its latency and errors in the metrics.
+fn log_query<E: fmt::Display>(operation: &str, sql: &str, start: Instant, rows: Result<u64, &E>) {
+    let elapsed = start.elapsed().as_secs_f64();
+    metrics().db_query_duration.with_label_values(&[operation]).observe(elapsed);
+    let duration_ms = elapsed * 1000.0;
+    match rows {
+        Ok(rows) => log!(Level::Debug, "query", operation = operation, query = sql, duration_ms = duration_ms, rows = rows),
+        Err(e) => {
+            metrics().db_errors.with_label_values(&["db"]).inc();
+            log!(Level::Warn, "query failed", operation = operation, query = sql, duration_ms = duration_ms, error = e.to_string())
+        }
+    }
+}
+
+fn contact_from_row(row: &Row) -> Contact {
+    let row_firstname : Option<String> =  row.get(1);
+    let row_phone : Option<String> =  row.get(3);
+    let row_email : Option<String> =  row.get(4);
+    Contact {
+        id: row.get(0),
+        firstname: row_firstname.unwrap_or(String::from("")),
+        lastname: row.get(2),
+        phone: row_phone.unwrap_or(String::from("")),
+        email: row_email.unwrap_or(String::from("")),
+        version: row.get(5),
+    }
+}
+
+#[cfg(test)]
+const DEFAULT_TEST_DSN: &str = "host=postgresql user=test password=test dbname=test";
+
+/// DSN of the database used by tests, overridable with `APP_TEST_DATABASE_URL`.
+#[cfg(test)]
+pub fn test_dsn() -> String {
+    std::env::var("APP_TEST_DATABASE_URL").unwrap_or_else(|_| DEFAULT_TEST_DSN.to_string())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::repository::{PgsqlRepository, Repository, Contact};
+    use crate::metrics::metrics;
+    use crate::query::ContactQuery;
+    use crate::repository::{test_dsn, Contact, Error, InMemoryRepository, PgsqlRepository, PoolConfig, Repository, SqliteRepository, Upserted};
+    use crate::testing::contact;
+    use rusqlite::params;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, SystemTime};
+    use tempfile::TempDir;
     use test_context::{test_context, AsyncTestContext};
-    use tokio_postgres::{NoTls};
-    use async_trait::async_trait;
+    use tokio_postgres::NoTls;
+
 
-    struct PgContext { repository: PgsqlRepository }
+    /// Runs each test against its own schema, so that tests running in
+    /// parallel neither see nor delete each other's contacts.
+    struct PgContext { repository: PgsqlRepository, schema: String }
 
-    #[async_trait]
     impl AsyncTestContext for PgContext {
         async fn setup() -> PgContext {
-            let (client, connection) = tokio_postgres::connect("host=postgresql user=test password=test dbname=test", NoTls).await.unwrap();
+            static SCHEMAS: AtomicUsize = AtomicUsize::new(0);
+            let schema = format!("repository_test_{}_{}", std::process::id(), SCHEMAS.fetch_add(1, Ordering::Relaxed));
+            let (client, connection) = tokio_postgres::connect(&test_dsn(), NoTls).await.unwrap();
+            tokio::spawn(connection);
+            client.batch_execute(&format!("DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0}", schema)).await.unwrap();
 
-            tokio::spawn(async move {
-                if let Err(e) = connection.await {
-                    eprintln!("connection error: {}", e);
-                }
-            });
-            PgContext {  repository: PgsqlRepository{ client } }
+            let repository = PgsqlRepository::with_config(&search_path_dsn(&schema), &PoolConfig::default()).await.unwrap();
+            repository.migrate_up().await.unwrap();
+            PgContext { repository, schema }
         }
 
         async fn teardown(self) {
-            self.repository.client.execute("DELETE FROM contact", &[]).await.unwrap();
+            let sql = format!("DROP SCHEMA {} CASCADE", self.schema);
+            self.repository.conn().await.unwrap().batch_execute(&sql).await.unwrap();
+        }
+    }
+
+    /// The test DSN, in either the key/value or the URL form, connecting with
+    /// `schema` as the search path.
+    fn search_path_dsn(schema: &str) -> String {
+        let dsn = test_dsn();
+        if dsn.contains("://") {
+            let separator = if dsn.contains('?') { '&' } else { '?' };
+            format!("{}{}options=-c%20search_path%3D{}", dsn, separator, schema)
+        } else {
+            format!("{} options='-c search_path={}'", dsn, schema)
+        }
+    }
+
+    impl PgContext {
+        /// Inserts a row with only the mandatory columns, leaving the others NULL.
+        async fn insert_lastname(&self, lastname: &str) -> i32 {
+            let row = self.repository.conn().await.unwrap().query_one("INSERT INTO contact (lastname) VALUES ($1) RETURNING id", &[&lastname]).await.unwrap();
+            row.get(0)
+        }
+    }
+
+    struct SqliteContext { repository: SqliteRepository, _dir: TempDir }
+
+    impl AsyncTestContext for SqliteContext {
+        async fn setup() -> SqliteContext {
+            let dir = tempfile::tempdir().unwrap();
+            let repository = SqliteRepository::open(&format!("sqlite://{}", dir.path().join("test.db").display())).unwrap();
+            repository.migrate_up().await.unwrap();
+            SqliteContext { repository, _dir: dir }
+        }
+    }
+
+    impl SqliteContext {
+        async fn insert_lastname(&self, lastname: &str) -> i32 {
+            let lastname = lastname.to_string();
+            self.repository.call(move |conn| Ok(conn.query_row("INSERT INTO contact (lastname) VALUES (?1) RETURNING id", params![lastname], |row| row.get(0))?)).await.unwrap()
         }
     }
 
+    struct MemoryContext { repository: InMemoryRepository }
+
+    impl AsyncTestContext for MemoryContext {
+        async fn setup() -> MemoryContext {
+            MemoryContext { repository: InMemoryRepository::default() }
+        }
+    }
+
+    impl MemoryContext {
+        async fn insert_lastname(&self, lastname: &str) -> i32 {
+            let mut contact = insert(&self.repository, lastname, "", "").await;
+            contact.firstname = String::new();
+            self.repository.update(&contact, None).await.unwrap().id
+        }
+    }
+
+    async fn insert(repository: &dyn Repository, lastname: &str, phone: &str, email: &str) -> Contact {
+        repository.save(&contact(lastname, phone, email)).await.unwrap()
+    }
+
+    fn ids(contacts: &[Contact]) -> Vec<i32> {
+        contacts.iter().map(|c| c.id).collect()
+    }
+
+    /// Behavior every backend must share, expanded once per test context.
+    macro_rules! repository_tests {
+        ($context:ident) => {
+            use super::*;
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn get_contact_no_contact(ctx: &$context) {
+                assert!(matches!(ctx.repository.get(i32::MAX).await, Err(Error::NotFound)), "no results should be found")
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn save_get_contact(ctx: &$context) {
+                let saved = ctx.repository.save(&contact("second", "0123456789", "e@mail.com")).await.unwrap();
+                assert_eq!(saved.version, 1);
+                assert_eq!(ctx.repository.get(saved.id).await.unwrap(), saved, "contact should be found")
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn save_generates_ids(ctx: &$context) {
+                let first = ctx.repository.save(&Contact { id: 7, ..contact("generated", "", "") }).await.unwrap();
+                let second = ctx.repository.save(&Contact { id: 7, ..contact("generated", "", "") }).await.unwrap();
+                assert!(second.id > first.id, "ids should be generated, ignoring the supplied one")
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn purged_ids_are_not_reused(ctx: &$context) {
+                let purged = insert(&ctx.repository, "reused", "", "").await.id;
+                ctx.repository.delete(purged).await.unwrap();
+                ctx.repository.purge(SystemTime::now() + Duration::from_secs(1)).await.unwrap();
+                assert!(insert(&ctx.repository, "reused", "", "").await.id > purged, "the purged id should not be handed out again")
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn save_get_contact_with_empty_fields(ctx: &$context) {
+                let id = ctx.insert_lastname("foo").await;
+                let contact = match ctx.repository.get(id).await {
+                    Ok(contact) => contact,
+                    Err(error) => {panic!("error : {:?}", error)},
+                };
+                assert_eq!(contact.id, id);
+                assert_eq!(contact.firstname, String::from(""));
+                assert_eq!(contact.lastname, "foo");
+                assert_eq!(contact.phone, String::from(""));
+                assert_eq!(contact.email, String::from(""))
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn update_contact(ctx: &$context) {
+                let mut contact = insert(&ctx.repository, "second", "0123456789", "e@mail.com").await;
+                contact.lastname = "third".to_string();
+                let updated = ctx.repository.update(&contact, None).await.unwrap();
+                assert_eq!(updated.version, contact.version + 1);
+                assert_eq!(ctx.repository.get(contact.id).await.unwrap().lastname, "third")
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn update_contact_stale_version(ctx: &$context) {
+                let contact = insert(&ctx.repository, "stale", "", "").await;
+                let updated = ctx.repository.update(&contact, Some(contact.version)).await.unwrap();
+                assert!(matches!(ctx.repository.update(&contact, Some(contact.version)).await, Err(Error::VersionMismatch)));
+                assert!(ctx.repository.update(&contact, Some(updated.version)).await.is_ok(), "current version should match")
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn update_contact_no_contact(ctx: &$context) {
+                let contact = Contact { id: i32::MAX, ..contact("second", "0123456789", "e@mail.com") };
+                assert!(matches!(ctx.repository.update(&contact, None).await, Err(Error::NotFound)));
+                assert!(matches!(ctx.repository.update(&contact, Some(1)).await, Err(Error::NotFound)))
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn delete_contact(ctx: &$context) {
+                let id = ctx.insert_lastname("foo").await;
+                assert_eq!(ctx.repository.delete(id).await.unwrap(), 1);
+                assert!(ctx.repository.get(id).await.is_err(), "contact should be deleted");
+                assert_eq!(ctx.repository.delete(id).await.unwrap(), 0)
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn trash_restore_purge(ctx: &$context) {
+                let contact = insert(&ctx.repository, "trashed", "", "").await;
+                ctx.repository.delete(contact.id).await.unwrap();
+                let query = ContactQuery { lastname: Some("trashed".to_string()), ..ContactQuery::default() };
+                assert_eq!(ctx.repository.list(&query).await.unwrap().total, 0, "deleted contacts should not be listed");
+                assert!(matches!(ctx.repository.update(&contact, None).await, Err(Error::NotFound)));
+                let trash = ctx.repository.trash(100, Some(contact.id - 1)).await.unwrap();
+                assert_eq!(trash.items.first().map(|c| &c.contact), Some(&contact));
+
+                let restored = ctx.repository.restore(contact.id).await.unwrap();
+                assert_eq!(restored.version, contact.version + 1);
+                assert_eq!(ctx.repository.get(contact.id).await.unwrap(), restored);
+                assert!(matches!(ctx.repository.restore(contact.id).await, Err(Error::NotFound)), "only deleted contacts can be restored");
+
+                ctx.repository.delete(contact.id).await.unwrap();
+                assert_eq!(ctx.repository.purge(SystemTime::now() - Duration::from_secs(60)).await.unwrap(), 0, "recent deletes should be kept");
+                assert!(ctx.repository.purge(SystemTime::now() + Duration::from_secs(1)).await.unwrap() >= 1);
+                assert!(matches!(ctx.repository.restore(contact.id).await, Err(Error::NotFound)), "purged contacts should be gone")
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn exists_sees_trashed_contacts(ctx: &$context) {
+                let contact = insert(&ctx.repository, "existing", "", "").await;
+                assert!(ctx.repository.exists(contact.id).await.unwrap());
+                ctx.repository.delete(contact.id).await.unwrap();
+                assert!(ctx.repository.exists(contact.id).await.unwrap(), "trashed contacts should exist");
+                assert!(!ctx.repository.exists(i32::MAX).await.unwrap())
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn upsert_restores_deleted_contacts(ctx: &$context) {
+                let contact = insert(&ctx.repository, "revived", "", "").await;
+                ctx.repository.delete(contact.id).await.unwrap();
+                let outcomes = ctx.repository.upsert(std::slice::from_ref(&contact)).await.unwrap();
+                assert!(matches!(&outcomes[0], Ok(Upserted::Updated(c)) if c.version == contact.version + 1));
+                assert_eq!(ctx.repository.get(contact.id).await.unwrap().version, contact.version + 1)
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn upsert_contacts(ctx: &$context) {
+                let existing = insert(&ctx.repository, "upserted", "", "").await;
+                let explicit_id = existing.id + 1000;
+                let outcomes = ctx.repository.upsert(&[
+                    Contact { lastname: "replaced".to_string(), ..existing.clone() },
+                    Contact { id: explicit_id, ..contact("upserted", "", "") },
+                    contact("upserted", "", ""),
+                ]).await.unwrap();
+                assert!(matches!(&outcomes[0], Ok(Upserted::Updated(c)) if c.lastname == "replaced" && c.version == existing.version + 1));
+                assert!(matches!(&outcomes[1], Ok(Upserted::Created(c)) if c.id == explicit_id && c.version == 1));
+                assert!(matches!(&outcomes[2], Ok(Upserted::Created(c)) if c.id != 0));
+                assert_eq!(ctx.repository.get(explicit_id).await.unwrap().lastname, "upserted");
+                assert!(insert(&ctx.repository, "upserted", "", "").await.id > explicit_id, "generated ids should skip upserted ones")
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn search_contacts(ctx: &$context) {
+                let lovelace = insert(&ctx.repository, "Lovelace", "", "ada@analytical.org").await.id;
+                let lovell = insert(&ctx.repository, "Lovell", "", "").await.id;
+                insert(&ctx.repository, "Babbage", "", "charles@analytical.org").await;
+
+                assert_eq!(ids(&ctx.repository.search("lovel", 10).await.unwrap().iter().map(|h| h.contact.clone()).collect::<Vec<_>>()), vec![lovelace, lovell]);
+                let hits = ctx.repository.search("lovlace", 10).await.unwrap();
+                assert_eq!(hits.first().map(|h| h.contact.id), Some(lovelace), "misspellings should match");
+                let hits = ctx.repository.search("ada analytical", 10).await.unwrap();
+                assert_eq!(hits.first().map(|h| h.contact.id), Some(lovelace), "the best match should come first");
+                assert!(ctx.repository.search("zyxwvut", 10).await.unwrap().is_empty());
+                assert_eq!(ctx.repository.search("analytical", 1).await.unwrap().len(), 1, "results should be limited")
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn list_contacts(ctx: &$context) {
+                let foo = ctx.insert_lastname("foo").await;
+                let bar = ctx.insert_lastname("bar").await;
+                let ids = ids(&ctx.repository.list(&ContactQuery::default()).await.unwrap().items);
+                assert!(ids.contains(&foo) && ids.contains(&bar), "both contacts should be listed")
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn list_contacts_filtered(ctx: &$context) {
+                let a = insert(&ctx.repository, "filtered", "+33123", "a@iroco.co").await.id;
+                let b = insert(&ctx.repository, "filtered", "+44123", "b@IROCO.CO").await.id;
+                let c = insert(&ctx.repository, "filtered", "+33456", "c@other.org").await.id;
+
+                let query = ContactQuery { lastname: Some("filtered".to_string()), email_domain: Some("iroco.co".to_string()), ..ContactQuery::default() };
+                let page = ctx.repository.list(&query).await.unwrap();
+                assert_eq!(page.total, 2);
+                assert_eq!(ids(&page.items), vec![a, b]);
+
+                let query = ContactQuery { lastname: Some("filtered".to_string()), phone_prefix: Some("+33".to_string()), ..ContactQuery::default() };
+                let page = ctx.repository.list(&query).await.unwrap();
+                assert_eq!(ids(&page.items), vec![a, c])
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn list_contacts_sorted_with_cursor(ctx: &$context) {
+                let a = insert(&ctx.repository, "paged", "", "b@mail.com").await.id;
+                let b = insert(&ctx.repository, "paged", "", "a@mail.com").await.id;
+                let c = insert(&ctx.repository, "paged", "", "a@mail.com").await.id;
+
+                let mut query: ContactQuery = serde_urlencoded::from_str("lastname=paged&sort=email,-id&limit=2").unwrap();
+                let page = ctx.repository.list(&query).await.unwrap();
+                assert_eq!(page.total, 3);
+                assert_eq!(ids(&page.items), vec![c, b]);
+                assert_eq!(page.next_cursor, Some(b));
+
+                query.after = page.next_cursor;
+                let page = ctx.repository.list(&query).await.unwrap();
+                assert_eq!(ids(&page.items), vec![a]);
+                assert_eq!(page.next_cursor, None)
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn list_contacts_after_deleted_cursor(ctx: &$context) {
+                let a = insert(&ctx.repository, "cursor", "", "").await.id;
+                let b = insert(&ctx.repository, "cursor", "", "").await.id;
+                let c = insert(&ctx.repository, "cursor", "", "").await.id;
+                ctx.repository.delete(b).await.unwrap();
+
+                let query: ContactQuery = serde_urlencoded::from_str(&format!("lastname=cursor&after={}", b)).unwrap();
+                assert_eq!(ids(&ctx.repository.list(&query).await.unwrap().items), vec![c], "a deleted cursor row should still page");
+                let query: ContactQuery = serde_urlencoded::from_str(&format!("lastname=cursor&after={}&sort=-id", b)).unwrap();
+                assert_eq!(ids(&ctx.repository.list(&query).await.unwrap().items), vec![a])
+            }
+
+            #[test_context($context)]
+            #[tokio::test]
+            async fn list_contacts_with_offset(ctx: &$context) {
+                insert(&ctx.repository, "offset", "", "").await;
+                let second = insert(&ctx.repository, "offset", "", "").await.id;
+
+                let query: ContactQuery = serde_urlencoded::from_str("lastname=offset&limit=1&offset=1").unwrap();
+                let page = ctx.repository.list(&query).await.unwrap();
+                assert_eq!(page.total, 2);
+                assert_eq!(ids(&page.items), vec![second])
+            }
+        };
+    }
+
+    mod postgres {
+        repository_tests!(PgContext);
+    }
+
+    mod sqlite {
+        repository_tests!(SqliteContext);
+    }
+
+    mod memory {
+        repository_tests!(MemoryContext);
+    }
+
     #[test_context(PgContext)]
     #[tokio::test]
-    async fn get_contact_no_contact(ctx: &PgContext) {
-        assert!(ctx.repository.get(12).await.is_err(), "no results should be found")
+    async fn upsert_isolates_failing_rows(ctx: &PgContext) {
+        let too_long = Contact { lastname: "x".repeat(300), ..contact("isolated", "", "") };
+        let outcomes = ctx.repository.upsert(&[contact("isolated", "", ""), too_long, contact("isolated", "", "")]).await.unwrap();
+        assert!(matches!(outcomes[1], Err(Error::Db(_))), "value too long should fail");
+        let query = ContactQuery { lastname: Some("isolated".to_string()), ..ContactQuery::default() };
+        assert_eq!(ctx.repository.list(&query).await.unwrap().total, 2, "the other rows should be committed")
     }
 
-    #[test_context(PgContext)]
     #[tokio::test]
-    async fn save_get_contact(ctx: &PgContext) {
-        let contact = Contact {
-            id: 13,
-            firstname: "first".to_string(),
-            lastname: "second".to_string(),
-            phone: "0123456789".to_string(),
-            email: "e@mail.com".to_string()
-        };
-        assert!(ctx.repository.save(&contact).await.is_ok(), "save should succeed");
-        assert!(ctx.repository.get(13).await.is_ok(), "contact should be found")
+    async fn pool_acquire_timeout() {
+        let config = PoolConfig { max_size: 1, acquire_timeout: Duration::from_millis(100), ..PoolConfig::default() };
+        let repository = PgsqlRepository::with_config(&test_dsn(), &config).await.unwrap();
+        let _held = repository.conn().await.unwrap();
+        assert!(matches!(repository.get(i32::MAX).await, Err(Error::Unavailable)), "acquire should time out")
     }
 
-    #[test_context(PgContext)]
     #[tokio::test]
-    async fn save_get_contact_with_empty_fields(ctx: &PgContext) {
-        ctx.repository.client.execute("INSERT INTO contact (id, lastname) VALUES ($1,$2)", &[&14, &"foo"],).await.unwrap();
-        let contact = match ctx.repository.get(14).await {
-            Ok(contact) => contact,
-            Err(error) => {panic!("error : {:?}", error)},
-        };
-        assert_eq!(contact.id, 14);
-        assert_eq!(contact.firstname, String::from(""));
-        assert_eq!(contact.lastname, "foo");
-        assert_eq!(contact.phone, String::from(""));
-        assert_eq!(contact.email, String::from(""))
+    async fn counts_only_store_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = SqliteRepository::open(&format!("sqlite://{}", dir.path().join("test.db").display())).unwrap();
+        repository.migrate_up().await.unwrap();
+        let errors = |kind: &str| metrics().db_errors.with_label_values(&[kind]).get();
+        let (not_found, unavailable) = (errors("not_found"), errors("unavailable"));
+
+        assert!(matches!(repository.get(i32::MAX).await, Err(Error::NotFound)));
+        assert_eq!(errors("not_found"), not_found, "a missing contact is not a store failure");
+        repository.close().await;
+        assert!(matches!(repository.get(1).await, Err(Error::Unavailable)));
+        assert!(errors("unavailable") > unavailable, "a closed connection should be counted")
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn pool_min_size() {
+        let config = PoolConfig { min_size: Some(2), max_size: 4, ..PoolConfig::default() };
+        let repository = PgsqlRepository::with_config(&test_dsn(), &config).await.unwrap();
+        assert_eq!(repository.pool_status().unwrap().connections, 2)
+    }
+}
@@ -0,0 +1,241 @@
+use crate::query::{ContactQuery, Page, SortField, SortKey};
+use crate::repository::{Contact, DeletedContact, Error, Repository, Upserted};
+use async_trait::async_trait;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI32, Ordering as AtomicOrdering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Keeps contacts in a map guarded by a mutex, for tests and local
+/// development without a database. Behaves like `PgsqlRepository`, down to
+/// the ordering and cursor rules of `list`.
+#[derive(Default)]
+pub struct InMemoryRepository {
+    contacts: Mutex<BTreeMap<i32, Contact>>,
+    /// Deleted contacts, out of `contacts` until restored. Locked after
+    /// `contacts` when both are needed.
+    trash: Mutex<BTreeMap<i32, DeletedContact>>,
+    /// Last generated id, never reused like a database sequence.
+    last_id: AtomicI32,
+}
+
+#[async_trait]
+impl Repository for InMemoryRepository {
+    async fn get(&self, id: i32) -> Result<Contact, Error> {
+        self.contacts.lock().unwrap().get(&id).cloned().ok_or(Error::NotFound)
+    }
+
+    async fn exists(&self, id: i32) -> Result<bool, Error> {
+        let contacts = self.contacts.lock().unwrap();
+        Ok(contacts.contains_key(&id) || self.trash.lock().unwrap().contains_key(&id))
+    }
+
+    async fn list(&self, query: &ContactQuery) -> Result<Page<Contact>, Error> {
+        let contacts = self.contacts.lock().unwrap();
+        let mut matching: Vec<&Contact> = contacts.values().filter(|c| matches(c, query)).collect();
+        let total = matching.len() as i64;
+
+        let keys = query.sort_keys();
+        if let Some(after) = query.after {
+            // like the SQL subqueries, a cursor row deleted since still counts,
+            // and an unknown one matches nothing
+            let trash = self.trash.lock().unwrap();
+            match contacts.get(&after).or_else(|| trash.get(&after).map(|c| &c.contact)) {
+                Some(cursor) => matching.retain(|c| compare(c, cursor, &keys) == Ordering::Greater),
+                None => matching.clear(),
+            }
+        }
+        matching.sort_by(|a, b| compare(a, b, &keys));
+
+        let limit = query.limit();
+        let mut items: Vec<Contact> = matching.into_iter()
+            .skip(query.offset() as usize)
+            .take(limit as usize + 1)
+            .cloned()
+            .collect();
+        let next_cursor = if items.len() as i64 > limit {
+            items.truncate(limit as usize);
+            items.last().map(|c| c.id)
+        } else {
+            None
+        };
+        Ok(Page { items, total, next_cursor })
+    }
+
+    async fn save(&self, contact: &Contact) -> Result<Contact, Error> {
+        let id = self.last_id.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+        let contact = Contact { id, version: 1, ..contact.clone() };
+        self.contacts.lock().unwrap().insert(id, contact.clone());
+        Ok(contact)
+    }
+
+    async fn update(&self, contact: &Contact, expected_version: Option<i32>) -> Result<Contact, Error> {
+        let mut contacts = self.contacts.lock().unwrap();
+        let existing = contacts.get_mut(&contact.id).ok_or(Error::NotFound)?;
+        if expected_version.is_some_and(|version| version != existing.version) {
+            return Err(Error::VersionMismatch);
+        }
+        *existing = Contact { version: existing.version + 1, ..contact.clone() };
+        Ok(existing.clone())
+    }
+
+    async fn delete(&self, id: i32) -> Result<u64, Error> {
+        let mut contacts = self.contacts.lock().unwrap();
+        let contact = match contacts.remove(&id) {
+            Some(contact) => contact,
+            None => return Ok(0),
+        };
+        self.trash.lock().unwrap().insert(id, DeletedContact { contact, deleted_at: SystemTime::now() });
+        Ok(1)
+    }
+
+    async fn trash(&self, limit: i64, after: Option<i32>) -> Result<Page<DeletedContact>, Error> {
+        let trash = self.trash.lock().unwrap();
+        let mut items: Vec<DeletedContact> = trash.values()
+            .filter(|c| after.is_none_or(|after| c.contact.id > after))
+            .take(limit as usize + 1)
+            .cloned()
+            .collect();
+        let next_cursor = if items.len() as i64 > limit {
+            items.truncate(limit as usize);
+            items.last().map(|c| c.contact.id)
+        } else {
+            None
+        };
+        Ok(Page { items, total: trash.len() as i64, next_cursor })
+    }
+
+    async fn restore(&self, id: i32) -> Result<Contact, Error> {
+        let mut contacts = self.contacts.lock().unwrap();
+        let deleted = self.trash.lock().unwrap().remove(&id).ok_or(Error::NotFound)?;
+        let contact = Contact { version: deleted.contact.version + 1, ..deleted.contact };
+        contacts.insert(id, contact.clone());
+        Ok(contact)
+    }
+
+    async fn purge(&self, before: SystemTime) -> Result<u64, Error> {
+        let mut trash = self.trash.lock().unwrap();
+        let count = trash.len();
+        trash.retain(|_, c| c.deleted_at >= before);
+        Ok((count - trash.len()) as u64)
+    }
+
+    async fn upsert(&self, contacts: &[Contact]) -> Result<Vec<Result<Upserted, Error>>, Error> {
+        let mut stored = self.contacts.lock().unwrap();
+        let mut trash = self.trash.lock().unwrap();
+        let mut outcomes = Vec::with_capacity(contacts.len());
+        for contact in contacts {
+            if contact.id == 0 {
+                let id = self.last_id.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+                let created = Contact { id, version: 1, ..contact.clone() };
+                stored.insert(id, created.clone());
+                outcomes.push(Ok(Upserted::Created(created)));
+                continue;
+            }
+            self.last_id.fetch_max(contact.id, AtomicOrdering::SeqCst);
+            let existing = stored.get(&contact.id).map(|c| c.version).or_else(|| trash.remove(&contact.id).map(|c| c.contact.version));
+            let row = Contact { version: existing.map_or(1, |version| version + 1), ..contact.clone() };
+            stored.insert(contact.id, row.clone());
+            outcomes.push(Ok(if existing.is_some() { Upserted::Updated(row) } else { Upserted::Created(row) }));
+        }
+        Ok(outcomes)
+    }
+}
+
+fn matches(contact: &Contact, query: &ContactQuery) -> bool {
+    if let Some(lastname) = &query.lastname {
+        if &contact.lastname != lastname {
+            return false;
+        }
+    }
+    if let Some(domain) = &query.email_domain {
+        let contact_domain = contact.email.split('@').nth(1).unwrap_or("");
+        if contact_domain.to_lowercase() != domain.to_lowercase() {
+            return false;
+        }
+    }
+    if let Some(prefix) = &query.phone_prefix {
+        if !contact.phone.starts_with(prefix.as_str()) {
+            return false;
+        }
+    }
+    true
+}
+
+fn compare(a: &Contact, b: &Contact, keys: &[SortKey]) -> Ordering {
+    for key in keys {
+        let ordering = match key.field {
+            SortField::Id => a.id.cmp(&b.id),
+            SortField::Firstname => a.firstname.cmp(&b.firstname),
+            SortField::Lastname => a.lastname.cmp(&b.lastname),
+            SortField::Phone => a.phone.cmp(&b.phone),
+            SortField::Email => a.email.cmp(&b.email),
+        };
+        let ordering = if key.descending { ordering.reverse() } else { ordering };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::ContactQuery;
+    use crate::repository::{Contact, Error, InMemoryRepository, Repository};
+    use crate::testing::contact;
+
+    #[tokio::test]
+    async fn save_get_update_delete() {
+        let repository = InMemoryRepository::default();
+        assert!(matches!(repository.get(1).await, Err(Error::NotFound)));
+        let saved = repository.save(&contact("foo", "", "")).await.unwrap();
+        assert_eq!((saved.id, saved.version), (1, 1));
+        assert_eq!(repository.save(&contact("foo", "", "")).await.unwrap().id, 2);
+        let updated = repository.update(&Contact { lastname: "bar".to_string(), ..saved.clone() }, Some(1)).await.unwrap();
+        assert_eq!(updated.version, 2);
+        assert_eq!(repository.get(1).await.unwrap().lastname, "bar");
+        assert!(matches!(repository.update(&saved, Some(1)).await, Err(Error::VersionMismatch)));
+        assert!(matches!(repository.update(&Contact { id: 3, ..saved }, None).await, Err(Error::NotFound)));
+        assert_eq!(repository.delete(1).await.unwrap(), 1);
+        assert_eq!(repository.delete(1).await.unwrap(), 0);
+        assert_eq!(repository.save(&contact("baz", "", "")).await.unwrap().id, 3, "ids should not be reused");
+    }
+
+    #[tokio::test]
+    async fn list_filtered() {
+        let repository = InMemoryRepository::default();
+        repository.save(&contact("a", "+33612345678", "a@Example.com")).await.unwrap();
+        repository.save(&contact("b", "+14155550100", "b@example.com")).await.unwrap();
+        repository.save(&contact("c", "+33700000000", "c@other.org")).await.unwrap();
+
+        let query: ContactQuery = serde_urlencoded::from_str("email_domain=EXAMPLE.com").unwrap();
+        let page = repository.list(&query).await.unwrap();
+        assert_eq!(page.items.iter().map(|c| c.id).collect::<Vec<i32>>(), vec![1, 2]);
+
+        let query: ContactQuery = serde_urlencoded::from_str("phone_prefix=%2B33&lastname=c").unwrap();
+        let page = repository.list(&query).await.unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.items[0].id, 3);
+    }
+
+    #[tokio::test]
+    async fn list_sorted_with_cursor() {
+        let repository = InMemoryRepository::default();
+        for lastname in ["b", "a", "b", "c"] {
+            repository.save(&contact(lastname, "", "")).await.unwrap();
+        }
+
+        let query: ContactQuery = serde_urlencoded::from_str("sort=-lastname&limit=2").unwrap();
+        let page = repository.list(&query).await.unwrap();
+        assert_eq!(page.total, 4);
+        assert_eq!(page.items.iter().map(|c| c.id).collect::<Vec<i32>>(), vec![4, 1]);
+        assert_eq!(page.next_cursor, Some(1));
+
+        let query: ContactQuery = serde_urlencoded::from_str("sort=-lastname&limit=2&after=1").unwrap();
+        let page = repository.list(&query).await.unwrap();
+        assert_eq!(page.items.iter().map(|c| c.id).collect::<Vec<i32>>(), vec![3, 2]);
+        assert_eq!(page.next_cursor, None);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::query::{ContactQuery, SortField};
+use bytes::BytesMut;
+use rusqlite::types::ToSqlOutput;
+use tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+
+/// The SQL flavours the listing statements are written in.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Dialect {
+    Postgres,
+    Sqlite,
+}
+
+impl Dialect {
+    fn placeholder(self, position: usize) -> String {
+        match self {
+            Dialect::Postgres => format!("${}", position),
+            Dialect::Sqlite => format!("?{}", position),
+        }
+    }
+
+    /// Lowercase domain of the email, empty without an `@`.
+    fn email_domain(self) -> &'static str {
+        match self {
+            Dialect::Postgres => "lower(split_part(email, '@', 2))",
+            Dialect::Sqlite => "lower(CASE WHEN instr(email, '@') > 0 THEN substr(email, instr(email, '@') + 1) ELSE '' END)",
+        }
+    }
+
+    fn starts_with(self, column: &str, prefix: &str) -> String {
+        match self {
+            Dialect::Postgres => format!("starts_with({}, {})", column, prefix),
+            Dialect::Sqlite => format!("substr({0}, 1, length({1})) = {1}", column, prefix),
+        }
+    }
+}
+
+/// A bound value, which either backend knows how to send.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Param {
+    Text(String),
+    Int(i32),
+    BigInt(i64),
+}
+
+impl From<String> for Param {
+    fn from(value: String) -> Param {
+        Param::Text(value)
+    }
+}
+
+impl From<i32> for Param {
+    fn from(value: i32) -> Param {
+        Param::Int(value)
+    }
+}
+
+impl From<i64> for Param {
+    fn from(value: i64) -> Param {
+        Param::BigInt(value)
+    }
+}
+
+impl ToSql for Param {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        match self {
+            Param::Text(value) => value.to_sql(ty, out),
+            Param::Int(value) => value.to_sql(ty, out),
+            Param::BigInt(value) => value.to_sql(ty, out),
+        }
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <String as ToSql>::accepts(ty) || <i32 as ToSql>::accepts(ty) || <i64 as ToSql>::accepts(ty)
+    }
+
+    to_sql_checked!();
+}
+
+impl rusqlite::ToSql for Param {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match self {
+            Param::Text(value) => rusqlite::ToSql::to_sql(value),
+            Param::Int(value) => rusqlite::ToSql::to_sql(value),
+            Param::BigInt(value) => rusqlite::ToSql::to_sql(value),
+        }
+    }
+}
+
+/// Accumulates `WHERE` conditions and their positional parameters so that
+/// user input never ends up interpolated into the SQL text.
+pub struct SqlBuilder {
+    dialect: Dialect,
+    conditions: Vec<String>,
+    pub params: Vec<Param>,
+}
+
+impl SqlBuilder {
+    pub fn new(dialect: Dialect) -> SqlBuilder {
+        SqlBuilder { dialect, conditions: Vec::new(), params: Vec::new() }
+    }
+
+    pub fn bind<T: Into<Param>>(&mut self, value: T) -> String {
+        self.params.push(value.into());
+        self.dialect.placeholder(self.params.len())
+    }
+
+    pub fn filter(&mut self, condition: String) {
+        self.conditions.push(condition)
+    }
+
+    pub fn where_clause(&self) -> String {
+        if self.conditions.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", self.conditions.join(" AND "))
+        }
+    }
+}
+
+/// The statements of `Repository::list`: `count` takes the first
+/// `count_params` of `params`, `select` all of them.
+pub struct ListStatements {
+    pub count: String,
+    pub select: String,
+    pub params: Vec<Param>,
+    pub count_params: usize,
+}
+
+impl ListStatements {
+    pub fn new(query: &ContactQuery, dialect: Dialect) -> ListStatements {
+        let mut sql = SqlBuilder::new(dialect);
+        sql.filter("deleted_at IS NULL".to_string());
+        if let Some(lastname) = &query.lastname {
+            let p = sql.bind(lastname.clone());
+            sql.filter(format!("lastname = {}", p));
+        }
+        if let Some(domain) = &query.email_domain {
+            let p = sql.bind(domain.clone());
+            sql.filter(format!("{} = lower({})", dialect.email_domain(), p));
+        }
+        if let Some(prefix) = &query.phone_prefix {
+            let p = sql.bind(prefix.clone());
+            sql.filter(dialect.starts_with("phone", &p));
+        }
+        let count = format!("SELECT count(*) FROM contact{}", sql.where_clause());
+        let count_params = sql.params.len();
+
+        let keys = query.sort_keys();
+        if let Some(after) = query.after {
+            // keyset pagination: rows strictly after the cursor row in sort order
+            let cursor = sql.bind(after);
+            let mut alternatives = Vec::new();
+            for (i, key) in keys.iter().enumerate() {
+                let mut terms: Vec<String> = keys[..i].iter()
+                    .map(|k| format!("{0} = (SELECT {0} FROM contact WHERE id = {1})", sort_column(k.field), cursor))
+                    .collect();
+                let op = if key.descending { "<" } else { ">" };
+                terms.push(format!("{0} {1} (SELECT {0} FROM contact WHERE id = {2})", sort_column(key.field), op, cursor));
+                alternatives.push(format!("({})", terms.join(" AND ")));
+            }
+            sql.filter(format!("({})", alternatives.join(" OR ")));
+        }
+        let order: Vec<String> = keys.iter()
+            .map(|k| format!("{} {}", sort_column(k.field), if k.descending { "DESC" } else { "ASC" }))
+            .collect();
+        // one row more than asked, to tell whether there is a next page
+        let limit_param = sql.bind(query.limit() + 1);
+        let offset_param = sql.bind(query.offset());
+        let select = format!("SELECT id, firstname, lastname, phone, email, version FROM contact{} ORDER BY {} LIMIT {} OFFSET {}",
+                             sql.where_clause(), order.join(", "), limit_param, offset_param);
+        ListStatements { count, select, params: sql.params, count_params }
+    }
+}
+
+fn sort_column(field: SortField) -> &'static str {
+    match field {
+        SortField::Id => "id",
+        SortField::Firstname => "COALESCE(firstname, '')",
+        SortField::Lastname => "lastname",
+        SortField::Phone => "COALESCE(phone, '')",
+        SortField::Email => "COALESCE(email, '')",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::ContactQuery;
+    use crate::repository::sql::{Dialect, ListStatements, Param};
+
+    #[test]
+    fn numbers_placeholders_per_dialect() {
+        let query: ContactQuery = serde_urlencoded::from_str("lastname=Doe&phone_prefix=%2B33&after=7&limit=5").unwrap();
+        let postgres = ListStatements::new(&query, Dialect::Postgres);
+        let sqlite = ListStatements::new(&query, Dialect::Sqlite);
+        assert_eq!(postgres.count, "SELECT count(*) FROM contact WHERE deleted_at IS NULL AND lastname = $1 AND starts_with(phone, $2)");
+        assert_eq!(sqlite.count, "SELECT count(*) FROM contact WHERE deleted_at IS NULL AND lastname = ?1 AND substr(phone, 1, length(?2)) = ?2");
+        assert_eq!(postgres.select.replace('$', "?"), sqlite.select.replace("substr(phone, 1, length(?2)) = ?2", "starts_with(phone, ?2)"));
+        assert_eq!(postgres.count_params, 2);
+        assert_eq!(postgres.params, vec![Param::Text("Doe".to_string()), Param::Text("+33".to_string()), Param::Int(7), Param::BigInt(6), Param::BigInt(0)]);
+    }
+}
@@ -0,0 +1,297 @@
+use crate::metrics::metrics;
+use crate::migration::{self, MigrationStatus};
+use crate::query::{ContactQuery, Page};
+use crate::repository::sql::{Dialect, ListStatements};
+use crate::repository::{log_query, Contact, DeletedContact, Error, Repository, Upserted};
+use async_trait::async_trait;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row, TransactionBehavior};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long a statement waits for another process holding the database lock.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stores contacts in a SQLite file, for edge deployments and laptops. The
+/// single connection is used from blocking tasks so the runtime never stalls
+/// on disk I/O.
+pub struct SqliteRepository {
+    /// Taken out by `close`, after which every operation fails as unavailable.
+    conn: Arc<Mutex<Option<Connection>>>,
+}
+
+impl SqliteRepository {
+    /// Opens `sqlite://path.db`, or a private in-memory database for `sqlite://:memory:`.
+    pub fn open(url: &str) -> Result<Self, Error> {
+        let path = url.strip_prefix("sqlite://").or_else(|| url.strip_prefix("sqlite:")).unwrap_or(url);
+        let conn = if path == ":memory:" { Connection::open_in_memory()? } else { Connection::open(path)? };
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(Self { conn: Arc::new(Mutex::new(Some(conn))) })
+    }
+
+    pub(super) async fn call<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut guard = conn.lock().unwrap();
+            match guard.as_mut() {
+                Some(conn) => f(conn),
+                None => {
+                    metrics().count_db_error(&Error::Unavailable);
+                    Err(Error::Unavailable)
+                }
+            }
+        })
+        .await
+        .map_err(|e| Error::Intern(e.to_string()))?
+    }
+
+    async fn execute(&self, operation: &'static str, sql: &'static str, params: Vec<Value>) -> Result<u64, Error> {
+        self.call(move |conn| {
+            let start = Instant::now();
+            let result = conn.execute(sql, params_from_iter(params)).map(|rows| rows as u64);
+            log_query(operation, sql, start, result.as_ref().copied());
+            Ok(result?)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl Repository for SqliteRepository {
+    async fn get(&self, id: i32) -> Result<Contact, Error> {
+        self.call(move |conn| {
+            let sql = "SELECT id, firstname, lastname, phone, email, version FROM contact WHERE id = ?1 AND deleted_at IS NULL";
+            let start = Instant::now();
+            let result = conn.query_row(sql, params![id], contact_from_row).optional();
+            log_query("get", sql, start, result.as_ref().map(|row| row.is_some() as u64));
+            result?.ok_or(Error::NotFound)
+        })
+        .await
+    }
+
+    async fn exists(&self, id: i32) -> Result<bool, Error> {
+        self.call(move |conn| {
+            let sql = "SELECT 1 FROM contact WHERE id = ?1";
+            let start = Instant::now();
+            let result = conn.query_row(sql, params![id], |_| Ok(())).optional();
+            log_query("exists", sql, start, result.as_ref().map(|row| row.is_some() as u64));
+            Ok(result?.is_some())
+        })
+        .await
+    }
+
+    async fn list(&self, query: &ContactQuery) -> Result<Page<Contact>, Error> {
+        let query = query.clone();
+        self.call(move |conn| {
+            let statements = ListStatements::new(&query, Dialect::Sqlite);
+            let start = Instant::now();
+            let result = conn.query_row(&statements.count, params_from_iter(&statements.params[..statements.count_params]), |row| row.get::<_, i64>(0));
+            log_query("count", &statements.count, start, result.as_ref().map(|_| 1));
+            let total = result?;
+
+            let limit = query.limit();
+            let start = Instant::now();
+            let result = conn.prepare(&statements.select).and_then(|mut statement| {
+                statement.query_map(params_from_iter(&statements.params), contact_from_row)?.collect::<Result<Vec<Contact>, _>>()
+            });
+            log_query("list", &statements.select, start, result.as_ref().map(|rows| rows.len() as u64));
+            let mut items = result?;
+
+            let next_cursor = if items.len() as i64 > limit {
+                items.truncate(limit as usize);
+                items.last().map(|c| c.id)
+            } else {
+                None
+            };
+            Ok(Page { items, total, next_cursor })
+        })
+        .await
+    }
+
+    async fn save(&self, contact: &Contact) -> Result<Contact, Error> {
+        let values = contact_values(contact);
+        self.call(move |conn| {
+            let sql = "INSERT INTO contact (firstname, lastname, phone, email) VALUES (?1, ?2, ?3, ?4) \
+                       RETURNING id, firstname, lastname, phone, email, version";
+            let start = Instant::now();
+            let result = conn.query_row(sql, params_from_iter(values), contact_from_row);
+            log_query("save", sql, start, result.as_ref().map(|_| 1));
+            Ok(result?)
+        })
+        .await
+    }
+
+    async fn update(&self, contact: &Contact, expected_version: Option<i32>) -> Result<Contact, Error> {
+        let mut values = vec![Value::Integer(contact.id.into())];
+        values.extend(contact_values(contact));
+        values.push(expected_version.map_or(Value::Null, |v| Value::Integer(v.into())));
+        let id = contact.id;
+        self.call(move |conn| {
+            let sql = "UPDATE contact SET firstname = ?2, lastname = ?3, phone = ?4, email = ?5, version = version + 1 \
+                       WHERE id = ?1 AND deleted_at IS NULL AND (?6 IS NULL OR version = ?6) \
+                       RETURNING id, firstname, lastname, phone, email, version";
+            let start = Instant::now();
+            let result = conn.query_row(sql, params_from_iter(values), contact_from_row).optional();
+            log_query("update", sql, start, result.as_ref().map(|row| row.is_some() as u64));
+            match result? {
+                Some(contact) => Ok(contact),
+                // nothing matched: tell a missing contact from a stale version
+                None => match expected_version {
+                    Some(_) if conn.query_row("SELECT 1 FROM contact WHERE id = ?1 AND deleted_at IS NULL", params![id], |_| Ok(())).optional()?.is_some() => {
+                        Err(Error::VersionMismatch)
+                    }
+                    _ => Err(Error::NotFound),
+                },
+            }
+        })
+        .await
+    }
+
+    async fn delete(&self, id: i32) -> Result<u64, Error> {
+        let sql = "UPDATE contact SET deleted_at = ?2 WHERE id = ?1 AND deleted_at IS NULL";
+        self.execute("delete", sql, vec![Value::Integer(id.into()), Value::Integer(millis(SystemTime::now()))]).await
+    }
+
+    async fn trash(&self, limit: i64, after: Option<i32>) -> Result<Page<DeletedContact>, Error> {
+        self.call(move |conn| {
+            let count = "SELECT count(*) FROM contact WHERE deleted_at IS NOT NULL";
+            let start = Instant::now();
+            let result = conn.query_row(count, [], |row| row.get::<_, i64>(0));
+            log_query("count", count, start, result.as_ref().map(|_| 1));
+            let total = result?;
+
+            let select = "SELECT id, firstname, lastname, phone, email, version, deleted_at FROM contact \
+                          WHERE deleted_at IS NOT NULL AND (?1 IS NULL OR id > ?1) ORDER BY id LIMIT ?2";
+            let start = Instant::now();
+            let result = conn.prepare(select).and_then(|mut statement| {
+                statement
+                    .query_map(params![after, limit + 1], |row| {
+                        let deleted_at: i64 = row.get(6)?;
+                        Ok(DeletedContact { contact: contact_from_row(row)?, deleted_at: UNIX_EPOCH + Duration::from_millis(deleted_at as u64) })
+                    })?
+                    .collect::<Result<Vec<DeletedContact>, _>>()
+            });
+            log_query("trash", select, start, result.as_ref().map(|rows| rows.len() as u64));
+            let mut items = result?;
+
+            let next_cursor = if items.len() as i64 > limit {
+                items.truncate(limit as usize);
+                items.last().map(|c| c.contact.id)
+            } else {
+                None
+            };
+            Ok(Page { items, total, next_cursor })
+        })
+        .await
+    }
+
+    async fn restore(&self, id: i32) -> Result<Contact, Error> {
+        self.call(move |conn| {
+            let sql = "UPDATE contact SET deleted_at = NULL, version = version + 1 WHERE id = ?1 AND deleted_at IS NOT NULL \
+                       RETURNING id, firstname, lastname, phone, email, version";
+            let start = Instant::now();
+            let result = conn.query_row(sql, params![id], contact_from_row).optional();
+            log_query("restore", sql, start, result.as_ref().map(|row| row.is_some() as u64));
+            result?.ok_or(Error::NotFound)
+        })
+        .await
+    }
+
+    async fn purge(&self, before: SystemTime) -> Result<u64, Error> {
+        self.execute("purge", "DELETE FROM contact WHERE deleted_at < ?1", vec![Value::Integer(millis(before))]).await
+    }
+
+    async fn upsert(&self, contacts: &[Contact]) -> Result<Vec<Result<Upserted, Error>>, Error> {
+        let contacts = contacts.to_vec();
+        self.call(move |conn| {
+            let insert = "INSERT INTO contact (firstname, lastname, phone, email) VALUES (?1, ?2, ?3, ?4) \
+                          RETURNING id, firstname, lastname, phone, email, version";
+            let upsert = "INSERT INTO contact (id, firstname, lastname, phone, email) VALUES (?1, ?2, ?3, ?4, ?5) \
+                          ON CONFLICT (id) DO UPDATE SET firstname = excluded.firstname, lastname = excluded.lastname, \
+                          phone = excluded.phone, email = excluded.email, version = contact.version + 1, deleted_at = NULL \
+                          RETURNING id, firstname, lastname, phone, email, version";
+            let mut tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let mut outcomes = Vec::with_capacity(contacts.len());
+            for contact in &contacts {
+                // a savepoint per row, rolled back when dropped, so that a failing row leaves the others
+                let savepoint = tx.savepoint()?;
+                let existed = contact.id != 0
+                    && savepoint.query_row("SELECT 1 FROM contact WHERE id = ?1", params![contact.id], |_| Ok(())).optional()?.is_some();
+                let start = Instant::now();
+                let (sql, result) = if contact.id == 0 {
+                    (insert, savepoint.query_row(insert, params_from_iter(contact_values(contact)), contact_from_row))
+                } else {
+                    let mut values = vec![Value::Integer(contact.id.into())];
+                    values.extend(contact_values(contact));
+                    (upsert, savepoint.query_row(upsert, params_from_iter(values), contact_from_row))
+                };
+                log_query("upsert", sql, start, result.as_ref().map(|_| 1));
+                match result {
+                    Ok(stored) => {
+                        savepoint.commit()?;
+                        outcomes.push(Ok(if existed { Upserted::Updated(stored) } else { Upserted::Created(stored) }));
+                    }
+                    Err(err) => outcomes.push(Err(Error::Sqlite(err))),
+                }
+            }
+            tx.commit()?;
+            Ok(outcomes)
+        })
+        .await
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        self.call(|conn| Ok(conn.query_row("SELECT 1", [], |_| Ok(()))?)).await
+    }
+
+    async fn schema_version(&self) -> Result<Option<i64>, Error> {
+        self.call(|conn| migration::sqlite::current_version(conn)).await
+    }
+
+    async fn migrate_up(&self) -> Result<Vec<i64>, Error> {
+        self.call(migration::sqlite::up).await
+    }
+
+    async fn migrate_down(&self) -> Result<Option<i64>, Error> {
+        self.call(migration::sqlite::down).await
+    }
+
+    async fn migration_status(&self) -> Result<Vec<MigrationStatus>, Error> {
+        self.call(|conn| migration::sqlite::status(conn)).await
+    }
+
+    async fn close(&self) {
+        let conn = self.conn.lock().unwrap().take();
+        drop(conn);
+    }
+}
+
+/// How `deleted_at` is stored, SQLite having no timestamp type.
+fn millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+/// The editable fields, in column order.
+fn contact_values(contact: &Contact) -> Vec<Value> {
+    vec![
+        Value::Text(contact.firstname.clone()),
+        Value::Text(contact.lastname.clone()),
+        Value::Text(contact.phone.clone()),
+        Value::Text(contact.email.clone()),
+    ]
+}
+
+fn contact_from_row(row: &Row) -> rusqlite::Result<Contact> {
+    Ok(Contact {
+        id: row.get(0)?,
+        firstname: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+        lastname: row.get(2)?,
+        phone: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+        email: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+        version: row.get(5)?,
+    })
+}
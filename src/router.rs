@@ -1,12 +1,20 @@
+use crate::extract::FromContext;
+use crate::middleware::{Middleware, Stack};
 use crate::{Context, Response};
 use async_trait::async_trait;
 use futures::future::Future;
-use hyper::{Method, StatusCode};
-use route_recognizer::{Params, Router as InternalRouter};
+use hyper::body::HttpBody;
+use hyper::{header, Body, Method, StatusCode};
+use route_recognizer::{Match, Params, Router as InternalRouter};
 use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Arc;
 
+/// Produces the response to a request. `T` tells apart the argument lists
+/// a handler function can take: the whole `Context`, or up to four extractors.
+/// Routes store handlers as `dyn Handler`, i.e. taking the `Context`.
 #[async_trait]
-pub trait Handler: Send + Sync + 'static {
+pub trait Handler<T = Context>: Send + Sync + 'static {
     async fn invoke(&self, context: Context) -> Response;
 }
 
@@ -22,45 +30,261 @@ where
     }
 }
 
+macro_rules! impl_handler {
+    ($($arg:ident),+) => {
+        #[async_trait]
+        impl<F: Send + Sync + 'static, Fut, $($arg),+> Handler<($($arg,)+)> for F
+        where
+            F: Fn($($arg),+) -> Fut,
+            Fut: Future + Send + 'static,
+            Fut::Output: IntoResponse,
+            $($arg: FromContext + Send + 'static),+
+        {
+            #[allow(non_snake_case)]
+            async fn invoke(&self, mut context: Context) -> Response {
+                $(
+                    let $arg = match $arg::from_context(&mut context).await {
+                        Ok(value) => value,
+                        Err(err) => return err.into_response(),
+                    };
+                )+
+                (self)($($arg),+).await.into_response()
+            }
+        }
+    };
+}
+
+impl_handler!(A);
+impl_handler!(A, B);
+impl_handler!(A, B, C);
+impl_handler!(A, B, C, D);
+
+/// Stores a handler of any argument list as one taking the `Context`.
+struct Erased<H, T> {
+    handler: H,
+    args: PhantomData<fn() -> T>,
+}
+
+#[async_trait]
+impl<H: Handler<T>, T: 'static> Handler for Erased<H, T> {
+    async fn invoke(&self, context: Context) -> Response {
+        self.handler.invoke(context).await
+    }
+}
+
+fn erase<H: Handler<T>, T: 'static>(handler: H) -> Arc<dyn Handler> {
+    Arc::new(Erased { handler, args: PhantomData })
+}
+
 pub struct RouterMatch<'a> {
     pub handler: &'a dyn Handler,
     pub params: Params,
+    pub middleware: &'a [Arc<dyn Middleware>],
+    pub pattern: Option<&'a str>,
+    /// Methods registered for the path, set when the request method itself is not.
+    pub allowed: Vec<Method>,
+}
+
+/// Methods a path answers to, stored in the request extensions when the
+/// request method is not one of them.
+#[derive(Clone)]
+pub struct AllowedMethods(pub Vec<Method>);
+
+/// The pattern of the route a request matched, such as `/contacts/:id`,
+/// stored in the request extensions.
+#[derive(Clone)]
+pub struct RoutePattern(pub String);
+
+struct Route {
+    /// `None` for routes answering every method.
+    methods: Option<Vec<Method>>,
+    pattern: String,
+    handler: Arc<dyn Handler>,
 }
 
 pub struct Router {
-    method_map: HashMap<Method, InternalRouter<Box<dyn Handler>>>,
+    method_map: HashMap<Method, InternalRouter<Arc<Route>>>,
+    any: InternalRouter<Arc<Route>>,
+    /// Every route in registration order, replayed when this router is mounted.
+    routes: Vec<Arc<Route>>,
+    middleware: Vec<Arc<dyn Middleware>>,
 }
 
 impl Router {
     pub fn new() -> Router {
         Router {
             method_map: HashMap::default(),
+            any: InternalRouter::new(),
+            routes: Vec::new(),
+            middleware: Vec::new(),
         }
     }
 
-    pub fn get(&mut self, path: &str, handler: Box<dyn Handler>) {
-        self.method_map
-            .entry(Method::GET)
-            .or_insert_with(InternalRouter::new)
-            .add(path, handler)
+    /// Registers middleware run, in registration order, around every request,
+    /// including those that match no route. Once the router is mounted into
+    /// another one, it only wraps the mounted routes.
+    pub fn middleware(&mut self, middleware: impl Middleware) {
+        self.middleware.push(Arc::new(middleware))
+    }
+
+    /// Registers a handler for several methods at once.
+    pub fn route<T: 'static>(&mut self, methods: &[Method], path: &str, handler: impl Handler<T>) {
+        self.add(Some(methods.to_vec()), path, erase(handler))
+    }
+
+    /// Registers a handler for every method not routed more specifically.
+    #[cfg(test)]
+    pub fn any<T: 'static>(&mut self, path: &str, handler: impl Handler<T>) {
+        self.add(None, path, erase(handler))
     }
 
-    #[allow(dead_code)]
-    pub fn post(&mut self, path: &str, handler: Box<dyn Handler>) {
-        self.method_map
-            .entry(Method::POST)
-            .or_insert_with(InternalRouter::new)
-            .add(path, handler)
+    pub fn get<T: 'static>(&mut self, path: &str, handler: impl Handler<T>) {
+        self.route(&[Method::GET], path, handler)
     }
 
-    pub fn route(&self, path: &str, method: &Method) -> RouterMatch<'_> {
-        match self.method_map.get(method).and_then(|r| r.recognize(path).ok()) {
-            Some(route) => RouterMatch { handler: &***route.handler(), params: route.params().clone() },
-            None => RouterMatch { handler: &not_found_handler, params: Params::new() }
+    pub fn post<T: 'static>(&mut self, path: &str, handler: impl Handler<T>) {
+        self.route(&[Method::POST], path, handler)
+    }
+
+    pub fn put<T: 'static>(&mut self, path: &str, handler: impl Handler<T>) {
+        self.route(&[Method::PUT], path, handler)
+    }
+
+    pub fn patch<T: 'static>(&mut self, path: &str, handler: impl Handler<T>) {
+        self.route(&[Method::PATCH], path, handler)
+    }
+
+    pub fn delete<T: 'static>(&mut self, path: &str, handler: impl Handler<T>) {
+        self.route(&[Method::DELETE], path, handler)
+    }
+
+    /// Overrides the `HEAD` response otherwise derived from the `GET` route.
+    #[cfg(test)]
+    pub fn head<T: 'static>(&mut self, path: &str, handler: impl Handler<T>) {
+        self.route(&[Method::HEAD], path, handler)
+    }
+
+    /// Overrides the automatic `OPTIONS` response listing the allowed methods.
+    #[cfg(test)]
+    pub fn options<T: 'static>(&mut self, path: &str, handler: impl Handler<T>) {
+        self.route(&[Method::OPTIONS], path, handler)
+    }
+
+    /// Registers the routes built by `build` under `prefix`, such as
+    /// `router.scope("/api/v1", |r| r.get("/contacts", ...))`.
+    pub fn scope(&mut self, prefix: &str, build: impl FnOnce(&mut Router)) {
+        let mut scope = Router::new();
+        build(&mut scope);
+        self.mount(prefix, scope);
+    }
+
+    /// Adds every route of `router` under `prefix`, wrapped in the
+    /// middleware registered on `router`.
+    pub fn mount(&mut self, prefix: &str, router: Router) {
+        for route in router.routes {
+            let handler: Arc<dyn Handler> = if router.middleware.is_empty() {
+                route.handler.clone()
+            } else {
+                Arc::new(Stack::nested(router.middleware.clone(), route.handler.clone()))
+            };
+            self.add(route.methods.clone(), &join_path(prefix, &route.pattern), handler);
+        }
+    }
+
+    fn add(&mut self, methods: Option<Vec<Method>>, path: &str, handler: Arc<dyn Handler>) {
+        let route = Arc::new(Route { methods: methods.clone(), pattern: path.to_string(), handler });
+        match methods {
+            Some(methods) => {
+                for method in methods {
+                    self.method_map.entry(method).or_default().add(path, route.clone());
+                }
+            }
+            None => self.any.add(path, route.clone()),
+        }
+        self.routes.push(route);
+    }
+
+    /// Finds the handler for a request. `HEAD` falls back to the `GET` route,
+    /// and a path registered for other methods only answers `OPTIONS` itself
+    /// and 405 to anything else.
+    pub fn find(&self, path: &str, method: &Method) -> RouterMatch<'_> {
+        if let Some(found) = self.recognize(path, method) {
+            return found;
+        }
+        if method == Method::HEAD {
+            if let Some(found) = self.recognize(path, &Method::GET) {
+                return found;
+            }
         }
+        if let Ok(route) = self.any.recognize(path) {
+            return self.found(route);
+        }
+        let (allowed, pattern) = self.allowed_methods(path);
+        let handler: &dyn Handler = match (allowed.is_empty(), method) {
+            (true, _) => &not_found_handler,
+            (false, &Method::OPTIONS) => &options_handler,
+            (false, _) => &method_not_allowed_handler,
+        };
+        RouterMatch { handler, params: Params::new(), middleware: &self.middleware, pattern, allowed }
+    }
+
+    fn found<'a>(&'a self, route: Match<&'a Arc<Route>>) -> RouterMatch<'a> {
+        RouterMatch {
+            handler: &*route.handler().handler,
+            params: route.params().clone(),
+            middleware: &self.middleware,
+            pattern: Some(&route.handler().pattern),
+            allowed: Vec::new(),
+        }
+    }
+
+    fn recognize(&self, path: &str, method: &Method) -> Option<RouterMatch<'_>> {
+        let route = self.method_map.get(method)?.recognize(path).ok()?;
+        Some(self.found(route))
+    }
+
+    /// Every method answering `path`, in a stable order, along with the
+    /// pattern of the route of the first of them, so that the label of a 405
+    /// does not depend on the hash map order.
+    fn allowed_methods(&self, path: &str) -> (Vec<Method>, Option<&str>) {
+        let mut matches: Vec<(&Method, &str)> = self
+            .method_map
+            .iter()
+            .filter_map(|(method, router)| Some((method, router.recognize(path).ok()?.handler().pattern.as_str())))
+            .collect();
+        matches.sort_by_key(|(m, _)| (method_rank(m), m.to_string()));
+        let pattern = match matches.first() {
+            Some((_, pattern)) => *pattern,
+            None => return (Vec::new(), None),
+        };
+        let mut allowed: Vec<Method> = matches.into_iter().map(|(m, _)| m.clone()).collect();
+        if allowed.contains(&Method::GET) && !allowed.contains(&Method::HEAD) {
+            allowed.push(Method::HEAD);
+        }
+        if !allowed.contains(&Method::OPTIONS) {
+            allowed.push(Method::OPTIONS);
+        }
+        allowed.sort_by_key(|m| (method_rank(m), m.to_string()));
+        (allowed, Some(pattern))
+    }
+}
+
+/// Joins a mount prefix and a route pattern, `/api` and `/` giving `/api`.
+fn join_path(prefix: &str, path: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    match path.trim_start_matches('/') {
+        "" if prefix.is_empty() => "/".to_string(),
+        "" => prefix.to_string(),
+        rest => format!("{}/{}", prefix, rest),
     }
 }
 
+fn method_rank(method: &Method) -> usize {
+    const ORDER: [Method; 7] =
+        [Method::GET, Method::HEAD, Method::POST, Method::PUT, Method::PATCH, Method::DELETE, Method::OPTIONS];
+    ORDER.iter().position(|m| m == method).unwrap_or(ORDER.len())
+}
+
 async fn not_found_handler(_cx: Context) -> Response {
     hyper::Response::builder()
         .status(StatusCode::NOT_FOUND)
@@ -68,6 +292,37 @@ async fn not_found_handler(_cx: Context) -> Response {
         .unwrap()
 }
 
+async fn method_not_allowed_handler(cx: Context) -> Response {
+    hyper::Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header(header::ALLOW, allow_header(&cx))
+        .body("METHOD NOT ALLOWED".into())
+        .unwrap()
+}
+
+async fn options_handler(cx: Context) -> Response {
+    hyper::Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(header::ALLOW, allow_header(&cx))
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn allow_header(cx: &Context) -> String {
+    let allowed = cx.req.extensions().get::<AllowedMethods>().map(|a| a.0.as_slice()).unwrap_or_default();
+    allowed.iter().map(Method::as_str).collect::<Vec<&str>>().join(", ")
+}
+
+/// Drops the body of a response to a `HEAD` request, keeping the
+/// `Content-Length` the `GET` response would have had.
+pub fn strip_body(resp: Response) -> Response {
+    let (mut parts, body) = resp.into_parts();
+    if let Some(length) = body.size_hint().exact() {
+        parts.headers.entry(header::CONTENT_LENGTH).or_insert_with(|| length.into());
+    }
+    Response::from_parts(parts, Body::empty())
+}
+
 pub trait IntoResponse: Send + Sized {
     fn into_response(self) -> Response;
 }
@@ -89,3 +344,164 @@ impl IntoResponse for String {
         Response::new(self.into())
     }
 }
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(err) => err.into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::middleware::{Middleware, Next};
+    use crate::router::{join_path, Router};
+    use crate::testing::TestApp;
+    use crate::{Context, Response};
+    use async_trait::async_trait;
+    use hyper::{Method, StatusCode};
+
+    async fn pattern(ctx: Context) -> String {
+        let id = ctx.params.find("id").unwrap_or("-");
+        format!("{} {} {}", ctx.req.method(), ctx.req.uri().path(), id)
+    }
+
+    async fn fixed(_ctx: Context) -> &'static str {
+        "fixed"
+    }
+
+    struct Tag;
+
+    #[async_trait]
+    impl Middleware for Tag {
+        async fn handle(&self, ctx: Context, next: Next<'_>) -> Response {
+            let mut resp = next.run(ctx).await;
+            resp.headers_mut().insert("x-tag", "nested".parse().unwrap());
+            resp
+        }
+    }
+
+    #[tokio::test]
+    async fn registers_several_methods() {
+        let mut router = Router::new();
+        router.route(&[Method::GET, Method::PUT], "/things/:id", pattern);
+        let app = TestApp::with_router(router);
+        assert_eq!(app.send(Method::PUT, "/things/3", None).await.text(), "PUT /things/3 3");
+        assert_eq!(app.get("/things/4").await.text(), "GET /things/4 4");
+        assert_eq!(app.send(Method::POST, "/things/4", None).await.header("allow"), Some("GET, HEAD, PUT, OPTIONS"));
+    }
+
+    #[test]
+    fn labels_not_allowed_with_the_first_allowed_route() {
+        let mut router = Router::new();
+        router.put("/things/special", fixed);
+        router.delete("/things/:id", fixed);
+        router.get("/things/:id", fixed);
+        let found = router.find("/things/special", &Method::POST);
+        assert_eq!(found.allowed, vec![Method::GET, Method::HEAD, Method::PUT, Method::DELETE, Method::OPTIONS]);
+        assert_eq!(found.pattern, Some("/things/:id"), "the GET route should label the 405")
+    }
+
+    #[tokio::test]
+    async fn any_answers_unrouted_methods() {
+        let mut router = Router::new();
+        router.get("/things", fixed);
+        router.any("/things", pattern);
+        let app = TestApp::with_router(router);
+        assert_eq!(app.get("/things").await.text(), "fixed");
+        assert_eq!(app.send(Method::DELETE, "/things", None).await.text(), "DELETE /things -");
+    }
+
+    #[tokio::test]
+    async fn explicit_head_and_options_win() {
+        let mut router = Router::new();
+        router.get("/things", pattern);
+        router.head("/things", fixed);
+        router.options("/things", fixed);
+        let app = TestApp::with_router(router);
+        assert_eq!(app.send(Method::HEAD, "/things", None).await.header("content-length"), Some("5"));
+        assert_eq!(app.send(Method::OPTIONS, "/things", None).await.text(), "fixed");
+    }
+
+    #[tokio::test]
+    async fn scopes_and_mounts() {
+        let mut nested = Router::new();
+        nested.middleware(Tag);
+        nested.get("/", fixed);
+        nested.get("/things/:id", pattern);
+
+        let mut router = Router::new();
+        router.scope("/api/v1", |r| {
+            r.get("/things/:id", pattern);
+            r.mount("/nested", nested);
+        });
+        let app = TestApp::with_router(router);
+
+        assert_eq!(app.get("/api/v1/things/1").await.text(), "GET /api/v1/things/1 1");
+        assert_eq!(app.get("/api/v1/things/1").await.header("x-tag"), None);
+        let resp = app.get("/api/v1/nested/things/2").await;
+        assert_eq!(resp.text(), "GET /api/v1/nested/things/2 2");
+        assert_eq!(resp.header("x-tag"), Some("nested"));
+        assert_eq!(app.get("/api/v1/nested").await.text(), "fixed");
+        assert_eq!(app.get("/things/1").await.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn joins_paths() {
+        assert_eq!(join_path("/api/", "/contacts"), "/api/contacts");
+        assert_eq!(join_path("/api", "/"), "/api");
+        assert_eq!(join_path("", "/"), "/");
+        assert_eq!(join_path("/", "contacts"), "/contacts");
+    }
+
+    #[tokio::test]
+    async fn unknown_path_is_not_found() {
+        let resp = TestApp::new().get("/nowhere").await;
+        assert_eq!(resp.status, StatusCode::NOT_FOUND);
+        assert_eq!(resp.text(), "NOT FOUND");
+        assert!(resp.header("x-request-id").is_some(), "global middleware should run for unmatched requests");
+    }
+
+    #[tokio::test]
+    async fn unregistered_method_is_not_allowed() {
+        let resp = TestApp::new().send(Method::DELETE, "/contacts", None).await;
+        assert_eq!(resp.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.header("allow"), Some("GET, HEAD, POST, OPTIONS"));
+
+        let resp = TestApp::new().send(Method::POST, "/contacts/1", None).await;
+        assert_eq!(resp.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.header("allow"), Some("GET, HEAD, PUT, PATCH, DELETE, OPTIONS"));
+    }
+
+    #[tokio::test]
+    async fn answers_options() {
+        let resp = TestApp::new().send(Method::OPTIONS, "/health/live", None).await;
+        assert_eq!(resp.status, StatusCode::NO_CONTENT);
+        assert_eq!(resp.header("allow"), Some("GET, HEAD, OPTIONS"));
+        assert_eq!(TestApp::new().send(Method::OPTIONS, "/nowhere", None).await.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn answers_head_from_get() {
+        let app = TestApp::new();
+        let get = app.get("/health/live").await;
+        let head = app.send(Method::HEAD, "/health/live", None).await;
+        assert_eq!(head.status, StatusCode::OK);
+        assert!(head.body.is_empty(), "HEAD response should have no body");
+        assert_eq!(head.header("content-length"), Some(get.body.len().to_string().as_str()));
+        assert_eq!(head.header("content-type"), get.header("content-type"));
+    }
+
+    #[tokio::test]
+    async fn extracts_params() {
+        let app = TestApp::new();
+        let body = r#"{"firstname":"","lastname":"Doe","phone":"","email":""}"#;
+        app.send(Method::POST, "/contacts", Some(body)).await;
+        assert_eq!(app.send(Method::POST, "/contacts", Some(body)).await.status, StatusCode::CREATED);
+        let resp = app.get("/contacts/2").await;
+        assert_eq!(resp.status, StatusCode::OK);
+        assert_eq!(resp.json()["lastname"], "Doe");
+    }
+}
@@ -0,0 +1,222 @@
+//! Contact search: query terms, highlighting, and the naive matching used by
+//! stores without full-text search.
+
+use crate::query::{DEFAULT_LIMIT, MAX_LIMIT};
+use crate::repository::Contact;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+/// Trigram similarity from which a word counts as a misspelling of a term.
+pub const FUZZY_THRESHOLD: f32 = 0.5;
+
+/// Query string of `GET /contacts/search`.
+#[derive(Deserialize, Debug)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<i64>,
+}
+
+impl SearchQuery {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+}
+
+/// A contact matching a search, with its relevance, higher being better.
+/// Scores are only comparable within the results of one search.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub contact: Contact,
+    pub score: f32,
+}
+
+/// A search hit as returned to clients, with the matching parts of each
+/// field wrapped in `<mark>` tags.
+#[derive(Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    pub contact: Contact,
+    pub score: f32,
+    pub highlight: BTreeMap<&'static str, String>,
+}
+
+impl SearchResult {
+    pub fn new(hit: SearchHit, terms: &[String]) -> SearchResult {
+        let highlight = highlight(&hit.contact, terms);
+        SearchResult { contact: hit.contact, score: hit.score, highlight }
+    }
+}
+
+/// Lowercased words of a query, everything but letters and digits being a
+/// separator.
+pub fn terms(q: &str) -> Vec<String> {
+    words(q).map(|(_, word)| word.to_lowercase()).collect()
+}
+
+/// Scores `contact` against every term, `None` when one of them matches no
+/// word of the names or email, neither as a prefix nor as a misspelling.
+/// Names weigh twice as much as the email.
+pub fn score(contact: &Contact, terms: &[String]) -> Option<f32> {
+    if terms.is_empty() {
+        return None;
+    }
+    let mut total = 0.0;
+    for term in terms {
+        let best = searched_fields(contact)
+            .flat_map(|(_, value, weight)| words(value).map(move |(_, word)| weight * word_score(term, word)))
+            .fold(0.0, f32::max);
+        if best == 0.0 {
+            return None;
+        }
+        total += best;
+    }
+    Some(total / terms.len() as f32)
+}
+
+/// Fields with a matching word, HTML-escaped and with the matches marked.
+pub fn highlight(contact: &Contact, terms: &[String]) -> BTreeMap<&'static str, String> {
+    searched_fields(contact)
+        .filter_map(|(name, value, _)| highlight_field(value, terms).map(|marked| (name, marked)))
+        .collect()
+}
+
+fn searched_fields(contact: &Contact) -> impl Iterator<Item = (&'static str, &str, f32)> {
+    vec![
+        ("firstname", contact.firstname.as_str(), 1.0),
+        ("lastname", contact.lastname.as_str(), 1.0),
+        ("email", contact.email.as_str(), 0.5),
+    ]
+    .into_iter()
+}
+
+/// 1 for a word starting with `term`, its similarity for a misspelling, else 0.
+fn word_score(term: &str, word: &str) -> f32 {
+    let word = word.to_lowercase();
+    if word.starts_with(term) {
+        return 1.0;
+    }
+    let similarity = similarity(term, &word);
+    if similarity >= FUZZY_THRESHOLD { similarity } else { 0.0 }
+}
+
+fn highlight_field(value: &str, terms: &[String]) -> Option<String> {
+    let mut marked = String::new();
+    let mut last = 0;
+    for (start, word) in words(value) {
+        let lowercase = word.to_lowercase();
+        // a prefix match marks the prefix only, a misspelling the whole word
+        let length = terms
+            .iter()
+            .filter_map(|term| {
+                if lowercase.starts_with(term.as_str()) {
+                    word.char_indices().nth(term.chars().count()).map(|(i, _)| i).or(Some(word.len()))
+                } else if similarity(term, &lowercase) >= FUZZY_THRESHOLD {
+                    Some(word.len())
+                } else {
+                    None
+                }
+            })
+            .max();
+        if let Some(length) = length {
+            escape(&mut marked, &value[last..start]);
+            marked.push_str("<mark>");
+            escape(&mut marked, &word[..length]);
+            marked.push_str("</mark>");
+            last = start + length;
+        }
+    }
+    if last == 0 {
+        return None;
+    }
+    escape(&mut marked, &value[last..]);
+    Some(marked)
+}
+
+fn escape(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Runs of letters and digits with their byte offset.
+fn words(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut rest = text.char_indices().peekable();
+    std::iter::from_fn(move || {
+        while rest.peek().is_some_and(|(_, c)| !c.is_alphanumeric()) {
+            rest.next();
+        }
+        let (start, _) = *rest.peek()?;
+        let mut end = start;
+        while let Some((i, c)) = rest.peek().copied().filter(|(_, c)| c.is_alphanumeric()) {
+            end = i + c.len_utf8();
+            rest.next();
+        }
+        Some((start, &text[start..end]))
+    })
+}
+
+/// Shared trigrams over all distinct trigrams of two lowercase words, padded
+/// the way `pg_trgm` does.
+fn similarity(a: &str, b: &str) -> f32 {
+    let (a, b) = (trigrams(a), trigrams(b));
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(&b).count() as f32 / union as f32
+}
+
+fn trigrams(word: &str) -> HashSet<[char; 3]> {
+    let padded: Vec<char> = "  ".chars().chain(word.chars()).chain(" ".chars()).collect();
+    padded.windows(3).map(|w| [w[0], w[1], w[2]]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repository::Contact;
+    use crate::search::{highlight, score, similarity, terms};
+    use crate::testing::contact;
+
+    fn ada() -> Contact {
+        Contact { id: 1, firstname: "Ada".to_string(), version: 1, ..contact("Lovelace", "", "ada@example.com") }
+    }
+
+    #[test]
+    fn splits_terms() {
+        assert_eq!(terms(" Ada  O'Neil-Smith "), vec!["ada", "o", "neil", "smith"]);
+        assert!(terms("@@").is_empty())
+    }
+
+    #[test]
+    fn similarity_matches_pg_trgm() {
+        assert_eq!(similarity("lovelace", "lovelace"), 1.0);
+        // SELECT similarity('lovlace', 'lovelace') answers 0.54545456
+        assert!((similarity("lovlace", "lovelace") - 0.545_454_56).abs() < 1e-6)
+    }
+
+    #[test]
+    fn scores_prefixes_and_misspellings() {
+        assert_eq!(score(&ada(), &terms("ada love")), Some(1.0));
+        assert!(score(&ada(), &terms("lovlace")).unwrap() < 1.0);
+        assert!(score(&ada(), &terms("example")).unwrap() < score(&ada(), &terms("ada")).unwrap(), "names should weigh more");
+        assert_eq!(score(&ada(), &terms("ada turing")), None, "every term should match")
+    }
+
+    #[test]
+    fn marks_matches() {
+        let marked = highlight(&ada(), &terms("ad lovlace"));
+        assert_eq!(marked["firstname"], "<mark>Ad</mark>a");
+        assert_eq!(marked["lastname"], "<mark>Lovelace</mark>");
+        assert_eq!(marked["email"], "<mark>ad</mark>a@example.com");
+
+        let contact = Contact { lastname: "<b>Lovelace</b>".to_string(), ..ada() };
+        assert_eq!(highlight(&contact, &terms("love"))["lastname"], "&lt;b&gt;<mark>Love</mark>lace&lt;/b&gt;");
+        assert!(!highlight(&contact, &terms("turing")).contains_key("lastname"))
+    }
+}
@@ -0,0 +1,125 @@
+use crate::logging::{log, Level};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// Completes on the first SIGINT or SIGTERM.
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("SIGINT handler can be installed");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("SIGTERM handler can be installed")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Fails readiness by setting `shutting_down`, then waits `grace` so that
+/// probes notice before the server stops accepting connections.
+pub async fn fail_readiness(shutting_down: &AtomicBool, grace: Duration) {
+    shutting_down.store(true, Ordering::Relaxed);
+    log!(Level::Info, "shutdown signal received, failing readiness", readiness_grace_secs = grace.as_secs());
+    tokio::time::sleep(grace).await;
+}
+
+/// How draining went, returned by `serve_with_drain`.
+pub struct Drained {
+    /// Whether every in-flight request finished before the timeout.
+    pub complete: bool,
+    /// End of the drain timeout, which the rest of the shutdown shares.
+    pub deadline: Instant,
+}
+
+/// Runs `server` until `shutdown` fires, then gives it at most `drain_timeout`
+/// to finish in-flight requests.
+///
+/// `server` is expected to stop accepting connections as soon as the future it
+/// was given through `make_server` resolves, like hyper's graceful shutdown.
+pub async fn serve_with_drain<S, F, E>(make_server: S, shutdown: F, drain_timeout: Duration) -> Drained
+where
+    S: FnOnce(oneshot::Receiver<()>) -> E,
+    E: Future,
+    F: Future<Output = ()>,
+{
+    let (drain_tx, drain_rx) = oneshot::channel();
+    let server = make_server(drain_rx);
+    tokio::pin!(server);
+    tokio::select! {
+        _ = &mut server => return Drained { complete: true, deadline: Instant::now() + drain_timeout },
+        _ = shutdown => {},
+    }
+    log!(Level::Info, "shutting down, draining connections", drain_timeout_secs = drain_timeout.as_secs());
+    let deadline = Instant::now() + drain_timeout;
+    let _ = drain_tx.send(());
+    let complete = tokio::time::timeout_at(deadline.into(), server).await.is_ok();
+    Drained { complete, deadline }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shutdown::{fail_readiness, serve_with_drain};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, Instant};
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn drains_within_timeout() {
+        let drained = serve_with_drain(
+            |drain| async move {
+                drain.await.unwrap();
+                sleep(Duration::from_millis(10)).await;
+            },
+            async {},
+            Duration::from_secs(1),
+        )
+        .await;
+        assert!(drained.complete)
+    }
+
+    #[tokio::test]
+    async fn fails_readiness_for_the_grace_period_before_draining() {
+        let shutting_down = AtomicBool::new(false);
+        let grace = Duration::from_millis(50);
+        let drained = serve_with_drain(
+            |drain| async {
+                while !shutting_down.load(Ordering::Relaxed) {
+                    sleep(Duration::from_millis(1)).await;
+                }
+                let not_ready_since = Instant::now();
+                drain.await.unwrap();
+                assert!(not_ready_since.elapsed() >= grace - Duration::from_millis(5));
+            },
+            fail_readiness(&shutting_down, grace),
+            Duration::from_secs(1),
+        )
+        .await;
+        assert!(drained.complete)
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_timeout() {
+        let drained = serve_with_drain(
+            |drain| async move {
+                drain.await.unwrap();
+                sleep(Duration::from_secs(10)).await;
+            },
+            async {},
+            Duration::from_millis(10),
+        )
+        .await;
+        assert!(!drained.complete);
+        assert!(drained.deadline <= std::time::Instant::now())
+    }
+}
@@ -0,0 +1,141 @@
+//! Test harness sending requests through the full service (router, global
+//! middleware and handlers) backed by an in-memory repository.
+
+use crate::repository::{Contact, InMemoryRepository, Repository};
+use crate::router::Router;
+use crate::{route, router, AppState};
+use bytes::Bytes;
+use hyper::header::{HeaderMap, CONTENT_TYPE};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Server, StatusCode};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Peer address reported for in-process requests.
+const LOCAL_PEER: ([u8; 4], u16) = ([127, 0, 0, 1], 0);
+
+pub struct TestApp {
+    pub state: Arc<AppState>,
+    router: Arc<Router>,
+}
+
+impl TestApp {
+    pub fn new() -> TestApp {
+        TestApp::with_repository(Arc::new(InMemoryRepository::default()))
+    }
+
+    pub fn with_repository(repository: Arc<dyn Repository + Send + Sync>) -> TestApp {
+        TestApp::with_state(AppState::new(repository))
+    }
+
+    pub fn with_state(state: AppState) -> TestApp {
+        TestApp { state: Arc::new(state), router: Arc::new(router()) }
+    }
+
+    /// Serves `router` instead of the service routes, for router tests.
+    pub fn with_router(router: Router) -> TestApp {
+        TestApp { state: Arc::new(AppState::new(Arc::new(InMemoryRepository::default()))), router: Arc::new(router) }
+    }
+
+    /// Routes the request in-process, as the server would for a connection.
+    pub async fn request(&self, req: Request<Body>) -> TestResponse {
+        let resp = route(self.router.clone(), req, self.state.clone(), LOCAL_PEER.into()).await.unwrap();
+        TestResponse::read(resp).await
+    }
+
+    pub async fn get(&self, uri: &str) -> TestResponse {
+        self.send(Method::GET, uri, None).await
+    }
+
+    /// Sends `body`, if any, as JSON.
+    pub async fn send(&self, method: Method, uri: &str, body: Option<&str>) -> TestResponse {
+        let builder = Request::builder().method(method).uri(uri);
+        let req = match body {
+            Some(body) => builder.header(CONTENT_TYPE, "application/json").body(Body::from(body.to_string())),
+            None => builder.body(Body::empty()),
+        };
+        self.request(req.unwrap()).await
+    }
+
+    /// Serves the app on an ephemeral local port until the test runtime stops,
+    /// for tests that need a real HTTP connection.
+    pub fn spawn(&self) -> SocketAddr {
+        let router = self.router.clone();
+        let state = self.state.clone();
+        let make_service = make_service_fn(move |conn: &AddrStream| {
+            let router = router.clone();
+            let state = state.clone();
+            let peer = conn.remote_addr();
+            async move {
+                Ok::<_, crate::Error>(service_fn(move |req| route(router.clone(), req, state.clone(), peer)))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(LOCAL_PEER)).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+}
+
+/// A contact ready to be saved, without a first name. Tests needing other
+/// values set them with struct update syntax.
+pub fn contact(lastname: &str, phone: &str, email: &str) -> Contact {
+    Contact {
+        id: 0,
+        firstname: String::new(),
+        lastname: lastname.to_string(),
+        phone: phone.to_string(),
+        email: email.to_string(),
+        version: 0,
+    }
+}
+
+/// Reads a whole response body as JSON.
+pub async fn json_body(resp: crate::Response) -> serde_json::Value {
+    TestResponse::read(resp).await.json()
+}
+
+/// A response with its body read, so that tests can assert on it repeatedly.
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    pub async fn read(resp: crate::Response) -> TestResponse {
+        let (parts, body) = resp.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        TestResponse { status: parts.status, headers: parts.headers, body }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|v| v.to_str().unwrap())
+    }
+
+    pub fn text(&self) -> &str {
+        std::str::from_utf8(&self.body).unwrap()
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap_or_else(|e| panic!("invalid JSON body {:?}: {}", self.text(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{TestApp, TestResponse};
+    use hyper::{Client, StatusCode};
+
+    #[tokio::test]
+    async fn serves_over_ephemeral_port() {
+        let app = TestApp::new();
+        let addr = app.spawn();
+        let resp = Client::new().get(format!("http://{}/health/live", addr).parse().unwrap()).await.unwrap();
+        let resp = TestResponse::read(resp).await;
+        assert_eq!(resp.status, StatusCode::OK);
+        assert_eq!(resp.json()["status"], "up");
+        assert!(resp.header("x-request-id").is_some(), "global middleware should run");
+    }
+}
@@ -0,0 +1,151 @@
+use serde::Serialize;
+use std::fmt;
+
+/// A single failing rule on a single field.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: &'static str,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields: Vec<&str> = self.errors.iter().map(|e| e.field).collect();
+        write!(f, "invalid fields: {}", fields.join(", "))
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+/// Collects every failing rule instead of stopping at the first one, so that
+/// clients can fix all their fields in a single round-trip.
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn new() -> Validator {
+        Validator::default()
+    }
+
+    pub fn required(&mut self, field: &'static str, value: &str) -> &mut Self {
+        if value.trim().is_empty() {
+            self.fail(field, "required", "must not be empty".to_string());
+        }
+        self
+    }
+
+    pub fn max_length(&mut self, field: &'static str, value: &str, max: usize) -> &mut Self {
+        if value.chars().count() > max {
+            self.fail(field, "max_length", format!("must be at most {} characters", max));
+        }
+        self
+    }
+
+    /// Empty values pass: combine with `required` for mandatory emails.
+    pub fn email(&mut self, field: &'static str, value: &str) -> &mut Self {
+        if !value.is_empty() && !is_email(value) {
+            self.fail(field, "email", "must be a valid email address".to_string());
+        }
+        self
+    }
+
+    /// Empty values pass: combine with `required` for mandatory phone numbers.
+    pub fn phone(&mut self, field: &'static str, value: &str) -> &mut Self {
+        if !value.is_empty() && !is_e164(value) {
+            self.fail(field, "phone", "must be an E.164 phone number such as +33123456789".to_string());
+        }
+        self
+    }
+
+    pub fn finish(&mut self) -> Result<(), ValidationErrors> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationErrors { errors: std::mem::take(&mut self.errors) })
+        }
+    }
+
+    fn fail(&mut self, field: &'static str, code: &'static str, message: String) {
+        self.errors.push(FieldError { field, code, message })
+    }
+}
+
+fn is_email(value: &str) -> bool {
+    let mut parts = value.splitn(2, '@');
+    let (local, domain) = match (parts.next(), parts.next()) {
+        (Some(local), Some(domain)) => (local, domain),
+        _ => return false,
+    };
+    !local.is_empty()
+        && !domain.contains('@')
+        && !value.chars().any(char::is_whitespace)
+        && domain.split('.').count() >= 2
+        && domain.split('.').all(|label| !label.is_empty())
+}
+
+fn is_e164(value: &str) -> bool {
+    match value.strip_prefix('+') {
+        Some(digits) => {
+            (1..=15).contains(&digits.len())
+                && digits.chars().all(|c| c.is_ascii_digit())
+                && !digits.starts_with('0')
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::validation::Validator;
+
+    #[test]
+    fn collects_every_failing_field() {
+        let errors = Validator::new()
+            .required("lastname", " ")
+            .max_length("firstname", "abcdef", 5)
+            .email("email", "not-an-email")
+            .phone("phone", "0123456789")
+            .finish()
+            .unwrap_err();
+        let fields: Vec<(&str, &str)> = errors.errors.iter().map(|e| (e.field, e.code)).collect();
+        assert_eq!(fields, vec![("lastname", "required"), ("firstname", "max_length"), ("email", "email"), ("phone", "phone")])
+    }
+
+    #[test]
+    fn valid_values_pass() {
+        assert!(Validator::new()
+            .required("lastname", "Doe")
+            .max_length("lastname", "Doe", 5)
+            .email("email", "john.doe@mail.example.com")
+            .phone("phone", "+33123456789")
+            .finish()
+            .is_ok())
+    }
+
+    #[test]
+    fn empty_optional_values_pass() {
+        assert!(Validator::new().email("email", "").phone("phone", "").finish().is_ok())
+    }
+
+    #[test]
+    fn rejects_malformed_values() {
+        for email in &["a@b", "@mail.com", "a@@mail.com", "a b@mail.com", "a@mail..com"] {
+            assert!(Validator::new().email("email", email).finish().is_err(), "{} should be rejected", email)
+        }
+        for phone in &["+", "+0123", "+1234567890123456", "+33 1 23", "33123456789"] {
+            assert!(Validator::new().phone("phone", phone).finish().is_err(), "{} should be rejected", phone)
+        }
+    }
+}